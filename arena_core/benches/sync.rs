@@ -0,0 +1,116 @@
+//! Compares the syncs of a large room diffing the json of the state against the changes recorded by the state.
+//!
+//! cargo bench -p arena_core --bench sync
+extern crate arena_core;
+#[macro_use] extern crate criterion;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+
+use arena_core::{Adapter, Arena, Change, ChangeTracker, EmptyState, JsonValue, LocalAdapter, LocalConn, Message, Room, State};
+use criterion::Criterion;
+
+const ENTITIES: usize = 1_000;
+const CONNECTIONS: usize = 50;
+
+#[derive(Debug, Serialize)]
+struct Entity {
+    id: usize,
+    name: String,
+    x: f32,
+    y: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct World {
+    entities: Vec<Entity>,
+    #[serde(skip)]
+    changes: Option<ChangeTracker>,
+}
+
+impl World {
+    fn new(tracked: bool) -> World {
+        World {
+            entities: (0..ENTITIES)
+                .map(|i| Entity { id: i, name: format!("entity {}", i), x: 0.0, y: 0.0 })
+                .collect(),
+            changes: if tracked { Some(ChangeTracker::new()) } else { None },
+        }
+    }
+}
+
+impl State for World {
+    fn to_json(&self) -> JsonValue {
+        json!(self)
+    }
+
+    fn on_connect(&mut self, _conn_id: &str, _options: &JsonValue, _room: &mut Room, _server: &mut Arena) {}
+
+    //every message moves one entity
+    fn on_message(&mut self, _conn_id: &str, msg: &Message, _room: &mut Room, _server: &mut Arena) {
+        let i = msg.data.as_u64().unwrap_or(0) as usize % ENTITIES;
+        self.entities[i].x += 1.0;
+
+        if let Some(changes) = &mut self.changes {
+            changes.replace(&format!("/entities/{}/x", i), &self.entities[i].x);
+        }
+    }
+
+    fn take_changes(&mut self) -> Option<Vec<Change>> {
+        self.changes.as_mut().map(|c| c.take())
+    }
+}
+
+/// Arena with a world room joined by all the connections
+fn setup(tracked: bool) -> (Arena, String, Vec<LocalConn>) {
+    let mut arena = Arena::with_main_room("main_room", Box::new(EmptyState));
+    let mut adapter = LocalAdapter::new();
+    adapter.listen(arena.clone()).unwrap();
+
+    let room_id = arena.add("world", Box::new(World::new(tracked))).unwrap();
+    let conns: Vec<LocalConn> = (0..CONNECTIONS)
+        .map(|_| adapter.connect().unwrap())
+        .collect();
+
+    for conn in &conns {
+        let c = arena.get_connection(conn.id()).unwrap();
+        arena.add_connection_to(&room_id, c).unwrap();
+    }
+
+    drain(&conns);
+    (arena, room_id, conns)
+}
+
+fn drain(conns: &[LocalConn]) {
+    for conn in conns {
+        while let Some(_) = conn.events().try_recv() {}
+    }
+}
+
+fn bench_sync(c: &mut Criterion, name: &str, tracked: bool) {
+    let (arena, room_id, conns) = setup(tracked);
+    let container = arena.get_room(&room_id).unwrap();
+    let conn_id = conns[0].id().to_string();
+
+    let mut i = 0;
+    c.bench_function(name, move |b| b.iter(|| {
+        i += 1;
+        container.lock().on_message(&conn_id, &Message::new("move", &json!(i)));
+        drain(&conns);
+    }));
+}
+
+fn diff_sync(c: &mut Criterion) {
+    bench_sync(c, "sync 1000 entities to 50 connections with json diff", false);
+}
+
+fn tracked_sync(c: &mut Criterion) {
+    bench_sync(c, "sync 1000 entities to 50 connections with recorded changes", true);
+}
+
+//the diff of a large state is slow, few samples are enough to compare them
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = diff_sync, tracked_sync
+}
+criterion_main!(benches);
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use parking_lot::RwLock;
+use crossbeam_channel as channel;
+
+use {Arena, ArenaError, ClientEvents, Connection, Identity, JsonValue, Message, RoomEvents};
+
+/// Transport used to serve an arena, the same arena can be served by several adapters at the same time
+pub trait Adapter: Send {
+    /// Name of the transport used in the logs
+    fn name(&self) -> String;
+
+    /// Accept connections until the transport stops, it blocks the current thread.
+    /// The connections are opened with `Arena::accept`, passing the user if the transport authenticates it.
+    /// Their inbound messages are sent with `Arena::send` and they are closed with `Arena::disconnect`.
+    fn listen(&mut self, arena: Arena) -> Result<(), ArenaError>;
+}
+
+/// Sending side of a connection opened by an adapter
+pub trait Outbound: Send {
+    /// Deliver an event to the client
+    fn deliver(&mut self, evt: &ClientEvents);
+
+    /// Close the transport, the connection was already closed in the arena
+    fn close(&mut self, reason: Option<String>);
+
+    /// If the transport can take another event. When it can't, it must wake up who delivers the events
+    /// once it can, like the task of a forward.
+    fn ready(&mut self) -> bool {
+        true
+    }
+}
+
+/// Deliver the pending events of a connection while the transport is ready, returns false once the connection
+/// is closed. The events that don't fit wait in the queue of the connection, where its outbound limit applies.
+pub fn deliver_events<O: Outbound + ?Sized>(conn: &Connection, events: &channel::Receiver<ClientEvents>, out: &mut O) -> bool {
+    loop {
+        //a closing connection drops what the transport can't take so the close doesn't wait for the client
+        let ready = out.ready();
+        if !ready && !conn.is_closing() {
+            return true;
+        }
+
+        match events.try_recv() {
+            Some(ClientEvents::CloseConnection(reason)) => {
+                out.close(reason);
+                return false;
+            },
+            Some(evt) => if ready {
+                out.deliver(&evt);
+            },
+            None => return true
+        }
+    }
+}
+
+/// Adapter for clients living in the same process, like bots or tests
+#[derive(Clone)]
+pub struct LocalAdapter {
+    arena: Arc<RwLock<Option<Arena>>>,
+}
+
+impl LocalAdapter {
+    pub fn new() -> LocalAdapter {
+        LocalAdapter {
+            arena: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn connect(&self) -> Result<LocalConn, ArenaError> {
+        self.connect_as(None)
+    }
+
+    /// Connect as an user, the local clients are trusted so the identity is not checked
+    pub fn connect_as(&self, identity: Option<Identity>) -> Result<LocalConn, ArenaError> {
+        let mut arena = self.arena.read().clone()
+            .ok_or(ArenaError::AdapterNotListening { name: self.name() })?;
+
+        let conn = arena.accept(None, identity)?;
+        Ok(LocalConn {
+            events: conn.listen(),
+            conn: conn,
+            arena: arena,
+        })
+    }
+}
+
+impl Adapter for LocalAdapter {
+    fn name(&self) -> String {
+        "local".to_string()
+    }
+
+    fn listen(&mut self, arena: Arena) -> Result<(), ArenaError> {
+        *self.arena.write() = Some(arena);
+        Ok(())
+    }
+}
+
+/// Connection opened by a LocalAdapter, the events are received from `events()`
+pub struct LocalConn {
+    conn: Connection,
+    events: channel::Receiver<ClientEvents>,
+    arena: Arena,
+}
+
+impl LocalConn {
+    pub fn id(&self) -> &str {
+        &self.conn.id
+    }
+
+    pub fn token(&self) -> String {
+        self.conn.token()
+    }
+
+    pub fn events(&self) -> &channel::Receiver<ClientEvents> {
+        &self.events
+    }
+
+    pub fn join_room(&self, room_id: &str) {
+        self.join_room_with(room_id, JsonValue::Null);
+    }
+
+    /// Join a room sending options like a password or a seat
+    pub fn join_room_with(&self, room_id: &str, options: JsonValue) {
+        self.arena.send(RoomEvents::JoinRoom(room_id.to_string(), self.conn.id.clone(), options));
+    }
+
+    /// Watch a room without playing on it
+    pub fn spectate(&self, room_id: &str, options: JsonValue) {
+        self.arena.send(RoomEvents::Spectate(room_id.to_string(), self.conn.id.clone(), options));
+    }
+
+    /// Join any room of the kind that accepts the connection, creating one if needed
+    pub fn join_or_create(&self, kind: &str, options: JsonValue) {
+        self.arena.send(RoomEvents::JoinOrCreate(kind.to_string(), self.conn.id.clone(), options));
+    }
+
+    pub fn close_room(&self, room_id: &str) {
+        self.arena.send(RoomEvents::CloseRoom(room_id.to_string(), self.conn.id.clone()));
+    }
+
+    pub fn resync(&self, room_id: &str) {
+        self.arena.send(RoomEvents::Resync(room_id.to_string(), self.conn.id.clone()));
+    }
+
+    pub fn send(&self, room_id: &str, msg: Message) {
+        self.arena.send(RoomEvents::Msg(room_id.to_string(), self.conn.id.clone(), msg));
+    }
+
+    pub fn close(mut self) {
+        let id = self.conn.id.clone();
+        self.arena.disconnect(&id, false);
+    }
+}
@@ -0,0 +1,86 @@
+use serde::Serialize;
+
+use JsonValue;
+
+/// Mutation of a state at a json pointer path (`/board/0/1`), sent to the clients as a json patch operation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Add(String, JsonValue),
+    Replace(String, JsonValue),
+    Remove(String),
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Add(path, _) | Change::Replace(path, _) | Change::Remove(path) => path
+        }
+    }
+
+    pub fn value(&self) -> Option<&JsonValue> {
+        match self {
+            Change::Add(_, value) | Change::Replace(_, value) => Some(value),
+            Change::Remove(_) => None
+        }
+    }
+
+    pub fn value_mut(&mut self) -> Option<&mut JsonValue> {
+        match self {
+            Change::Add(_, value) | Change::Replace(_, value) => Some(value),
+            Change::Remove(_) => None
+        }
+    }
+
+    /// Path split in its unescaped segments
+    pub fn segments(&self) -> Vec<String> {
+        self.path().split('/')
+            .skip(1)
+            .map(|s| s.replace("~1", "/").replace("~0", "~"))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Change::Add(path, value) => json!({"op": "add", "path": path, "value": value}),
+            Change::Replace(path, value) => json!({"op": "replace", "path": path, "value": value}),
+            Change::Remove(path) => json!({"op": "remove", "path": path}),
+        }
+    }
+}
+
+/// Record of the mutations of a state, returned by `State::take_changes` so the syncs only
+/// serialize the values that changed instead of diffing the whole json for every connection.
+///
+/// Every mutation of the state must be recorded, otherwise the clients will miss it.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeTracker {
+    changes: Vec<Change>,
+}
+
+impl ChangeTracker {
+    pub fn new() -> ChangeTracker {
+        ChangeTracker::default()
+    }
+
+    /// Add a field to an object or insert an element in an array
+    pub fn add<T: Serialize>(&mut self, path: &str, value: &T) {
+        self.changes.push(Change::Add(path.to_string(), json!(value)));
+    }
+
+    pub fn replace<T: Serialize>(&mut self, path: &str, value: &T) {
+        self.changes.push(Change::Replace(path.to_string(), json!(value)));
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.changes.push(Change::Remove(path.to_string()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Changes recorded since the last call
+    pub fn take(&mut self) -> Vec<Change> {
+        ::std::mem::replace(&mut self.changes, vec![])
+    }
+}
@@ -13,8 +13,11 @@ extern crate rayon;
 
 use downcast_rs::Downcast;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use parking_lot::{Mutex, RwLock};
 use json_patch::diff;
 use crossbeam_channel as channel;
@@ -22,23 +25,109 @@ use rayon::prelude::*;
 use failure::Error;
 
 pub use serde_json::{Value as JsonValue};
+pub use matchmaker::{Matchmaker, MatchRules, Ticket};
+pub use adapter::{Adapter, Outbound, LocalAdapter, LocalConn, deliver_events};
+pub use metrics::{Metrics, CompressionStats, QueueStats};
+pub use metadata::Metadata;
+pub use visibility::{Visibility, Visible, SyncView, OwnerView, public_view};
+pub use changes::{Change, ChangeTracker};
+pub use outbound::{OutboundLimit, OutboundPolicy};
+
+mod matchmaker;
+mod adapter;
+mod metrics;
+mod metadata;
+mod visibility;
+mod changes;
+mod outbound;
 
 #[derive(Debug, Fail)]
-enum ArenaError {
-    #[fail(display = "unknow error: {}", reason)]
-    Unknow {
+pub enum ArenaError {
+    #[fail(display = "Room {} doesn't exists.", id)]
+    RoomNotFound {
+        id: RoomId
+    },
+
+    #[fail(display = "Room {} already exists.", id)]
+    RoomAlreadyExists {
+        id: RoomId
+    },
+
+    #[fail(display = "Room {} full of connections.", id)]
+    RoomFull {
+        id: RoomId
+    },
+
+    #[fail(display = "Room {} is not idle.", id)]
+    RoomNotIdle {
+        id: RoomId
+    },
+
+    #[fail(display = "Not found a main room.")]
+    NoMainRoom,
+
+    #[fail(display = "Connection rejected: {}", reason)]
+    ConnectionRejected {
         reason: String
-    }
+    },
+
+    #[fail(display = "Invalid connection id {}.", id)]
+    InvalidConnection {
+        id: ConnId
+    },
+
+    #[fail(display = "Invalid or expired resume token.")]
+    InvalidResumeToken,
+
+    #[fail(display = "Not found a state builder for the kind '{}'.", kind)]
+    StateBuilderNotFound {
+        kind: String
+    },
+
+    #[fail(display = "Invalid arena snapshot: {}", reason)]
+    InvalidSnapshot {
+        reason: String
+    },
+
+    #[fail(display = "Not found match rules for the kind '{}'.", kind)]
+    MatchRulesNotFound {
+        kind: String
+    },
+
+    #[fail(display = "Party {} doesn't fit in a match of {} connections.", party, players)]
+    PartyTooLarge {
+        party: String,
+        players: usize
+    },
+
+    #[fail(display = "Invalid metadata value for '{}': {}", key, reason)]
+    InvalidMetadata {
+        key: String,
+        reason: String
+    },
+
+    #[fail(display = "Unauthorized: {}", reason)]
+    Unauthorized {
+        reason: String
+    },
+
+    #[fail(display = "Adapter {} is not listening.", name)]
+    AdapterNotListening {
+        name: String
+    },
 
-    //todo add errors
+    #[fail(display = "Adapter {} failed: {}", name, reason)]
+    AdapterFailed {
+        name: String,
+        reason: String
+    },
 }
 
 type ConnId = String;
 type RoomId = String;
 
-trait Adapter {
-
-}
+/// Max time that the tick loop waits before checking again for rooms to update
+const MAX_TICK_WAIT_MS: u64 = 10;
 
 struct Client {
 
@@ -81,6 +170,10 @@ pub trait ClientHandler {
         println!("client on reject join {}:{}", id, reason);
     }
 
+    fn on_reject_join_or_create(&mut self, kind: &str, reason: &str) {
+        println!("client on reject join or create {}:{}", kind, reason);
+    }
+
     fn on_join_room(&mut self, id: &str) {
         println!("client on join room {}", id);
     }
@@ -88,6 +181,10 @@ pub trait ClientHandler {
     fn on_sync(&mut self, room_id: &str, msg: &JsonValue) {
         println!("on client sync {}", msg);
     }
+
+    fn on_snapshot(&mut self, room_id: &str, msg: &JsonValue) {
+        println!("on client snapshot {}:{}", room_id, msg);
+    }
 }
 
 pub struct LocalClient {
@@ -129,13 +226,17 @@ impl LocalClient {
         let my_id = self.inner.read().id.clone();
         let inner = self.inner.clone();
 
-        for evt in &self.conn.in_recv {
+        for evt in self.conn.listen() {
             println!(" # - # - # - # - # - # - # - # CLIENT EVT: {:?}", evt);
             match evt {
                 ClientEvents::Msg(room_id, msg) => {
                     if msg.event == "sync" {
                         println!("\\\\ CLIENT SYNC -> {:?}", msg.data);
                         inner.write().handler.on_sync(&room_id, &msg.data);
+                    } else if msg.event == "snapshot" {
+                        let mut c = inner.write();
+                        c.rooms.insert(room_id.to_string(), msg.data["state"].clone());
+                        c.handler.on_snapshot(&room_id, &msg.data);
                     }
                 },
                 ClientEvents::CloseConnection(reason) => {
@@ -146,10 +247,10 @@ impl LocalClient {
                     c.handler.on_close_connection(reason.clone());
                     break;
                 },
-                ClientEvents::JoinRoom(room_id, opt_reason) => {
-                    match opt_reason {
-                        Some(reason) => {
-                            inner.write().handler.on_reject_join(&room_id, &reason);
+                ClientEvents::JoinRoom(room_id, opt_err) => {
+                    match opt_err {
+                        Some(err) => {
+                            inner.write().handler.on_reject_join(&room_id, &err.to_string());
                         },
                         None => {
                             let mut c = inner.write();
@@ -158,6 +259,9 @@ impl LocalClient {
                         }
                     }
                 },
+                ClientEvents::RejectJoinOrCreate(kind, err) => {
+                    inner.write().handler.on_reject_join_or_create(&kind, &err.to_string());
+                },
                 _ => {}
             } 
         }
@@ -182,9 +286,10 @@ impl Message {
 
 #[derive(Debug)]
 pub enum ClientEvents {
-    OpenConnection(ConnId),
+    OpenConnection(ConnId, String), //id, resume token
     CloseConnection(Option<String>), //reason?
-    JoinRoom(RoomId, Option<String>), //roomid, error?
+    JoinRoom(RoomId, Option<ArenaError>), //roomid, error?
+    RejectJoinOrCreate(String, ArenaError), //kind, error, no room of the kind was joined
     CloseRoom(RoomId, String), //roomid, reason
     Msg(RoomId, Message) //todo rename to sync?
 }
@@ -193,10 +298,15 @@ pub enum ClientEvents {
 pub enum RoomEvents {
     OpenConnection(Connection),
     CloseConnection(ConnId),
-    JoinRoom(RoomId, ConnId),
+    JoinRoom(RoomId, ConnId, JsonValue), //roomid, conn_id, options sent by the client
+    JoinOrCreate(String, ConnId, JsonValue), //kind, conn_id, options
+    Spectate(RoomId, ConnId, JsonValue), //roomid, conn_id, options
     CloseRoom(RoomId, ConnId),
     Broadcast(RoomId, Message), //room msg
     Msg(RoomId, ConnId, Message), //room, conn_id, msg
+    DestroyRoom(RoomId),
+    ResumeConnection(ConnId),
+    Resync(RoomId, ConnId),
 }
 
 //todo rename to octopus, hive? or other thing because arena seems to be already used
@@ -217,6 +327,31 @@ pub struct RoomContainer {
     server: Arena,
 }
 
+thread_local! {
+    /// Rooms whose state callbacks are running on this thread, their containers are locked by it
+    static RUNNING_ROOMS: RefCell<Vec<RoomId>> = RefCell::new(vec![]);
+}
+
+/// Marks a room as running on this thread until it's dropped
+struct RunningRoom;
+
+impl RunningRoom {
+    fn enter(id: &str) -> RunningRoom {
+        RUNNING_ROOMS.with(|rooms| rooms.borrow_mut().push(id.to_string()));
+        RunningRoom
+    }
+
+    fn contains(id: &str) -> bool {
+        RUNNING_ROOMS.with(|rooms| rooms.borrow().iter().any(|r| r == id))
+    }
+}
+
+impl Drop for RunningRoom {
+    fn drop(&mut self) {
+        RUNNING_ROOMS.with(|rooms| rooms.borrow_mut().pop());
+    }
+}
+
 impl RoomContainer {
     pub fn new(id: &str, kind: &str, state: Box<State>, server: Arena) -> RoomContainer {
         let json_val = state.to_json();
@@ -233,54 +368,229 @@ impl RoomContainer {
         self.room.id()
     }
 
+    pub fn kind(&self) -> String {
+        self.kind.clone()
+    }
+
+    pub fn room(&self) -> &Room {
+        &self.room
+    }
+
+    pub fn state(&self) -> &State {
+        &*self.state
+    }
+
     pub fn is_full(&self) -> bool {
         self.room.is_full()
     }
 
-    pub fn add_connection(&mut self, conn: Connection) -> Result<(), String> {
-        if self.room.is_full() {
-            Err("Room full of connections.".to_string())
+    pub fn add_connection(&mut self, conn: Connection, options: &JsonValue) -> Result<(), ArenaError> {
+        if !self.is_idle() {
+            Err(ArenaError::RoomNotIdle { id: self.id() })
+        } else if self.room.is_full() {
+            Err(ArenaError::RoomFull { id: self.id() })
         } else {
-            let id = conn.id.clone();
-            self.room.add_conn(conn, &*self.state)
-                .map_err(move |e| format!("Room: {}, Connection: {} -> {}", self.id(), id, e))
+            self.room.add_conn(conn, options, &*self.state)
+        }
+    }
+
+    /// Add a connection that only watches the room, it doesn't take a seat of max_connections
+    pub fn add_spectator(&mut self, conn: Connection, options: &JsonValue) -> Result<(), ArenaError> {
+        if !self.is_idle() {
+            return Err(ArenaError::RoomNotIdle { id: self.id() });
+        }
+
+        if self.room.connections.contains_key(&conn.id) {
+            return Err(ArenaError::ConnectionRejected { reason: format!("{} already joined the room.", conn.id) });
+        }
+
+        self.state.validate_spectator(&conn, options)?;
+        self.room.insert_conn(conn, Role::Spectator);
+
+        Ok(())
+    }
+
+    /// Add a group of players, all of them or none if one can't be added.
+    /// The repeated connections and the ones already in the room are skipped, returns the ids of the ones added.
+    pub fn add_connections(&mut self, conns: Vec<Connection>) -> Result<Vec<ConnId>, ArenaError> {
+        if !self.is_idle() {
+            return Err(ArenaError::RoomNotIdle { id: self.id() });
+        }
+
+        let mut ids: HashSet<ConnId> = HashSet::new();
+        let conns: Vec<Connection> = conns.into_iter()
+            .filter(|c| !self.room.connections.contains_key(&c.id) && ids.insert(c.id.clone()))
+            .collect();
+
+        if let Some(max) = self.room.max_connections {
+            if self.room.players_len() + conns.len() > max {
+                return Err(ArenaError::RoomFull { id: self.id() });
+            }
+        }
+
+        for conn in &conns {
+            self.state.validate_connection(conn, &JsonValue::Null)?;
+        }
+
+        let added = conns.iter().map(|c| c.id.clone()).collect();
+        for conn in conns {
+            self.room.insert_conn(conn, Role::Player);
         }
+
+        Ok(added)
     }
 
     pub fn remove_connection(&mut self, conn_id: &str) {
         let opt_conn = self.room.connections.remove(conn_id);
         match opt_conn {
-            Some((c, _)) => {
+            Some(rc) => {
+                let _running = RunningRoom::enter(&self.room.id);
                 self.state.on_disconnect(conn_id, &mut self.room, &mut self.server);
-                c.dispatch(ClientEvents::CloseRoom(self.room.id(), "".to_string()));
+                rc.conn.dispatch(ClientEvents::CloseRoom(self.room.id(), "".to_string()));
                 self.sync();
             },
             _ => {}
         }
     }
 
+    /// Send again the rooms info to a connection resumed after a disconnection
+    pub fn resume_connection(&mut self, conn_id: &str) {
+        match self.room.connections.get(conn_id) {
+            Some(rc) => rc.conn.dispatch(ClientEvents::JoinRoom(self.room.id(), None)),
+            None => return
+        }
+
+        //the pending changes are already in the snapshot
+        self.flush_pending();
+        self.room.send_snapshot(conn_id, &*self.state);
+    }
+
+    /// Send the whole state to a connection that lost track of the patches
+    pub fn resync(&mut self, conn_id: &str) {
+        if !self.room.connections.contains_key(conn_id) {
+            println!("Connection {} can't resync {}:{} without joining it.", conn_id, self.kind, self.id());
+            return;
+        }
+
+        self.flush_pending();
+        self.room.send_snapshot(conn_id, &*self.state);
+    }
+
+    /// Sync now or later depending on the sync policy of the room
+    fn request_sync(&mut self) {
+        let now = match self.room.sync_policy {
+            SyncPolicy::Immediate => true,
+            SyncPolicy::Interval(_) => false,
+            SyncPolicy::MaxRate(_) => Instant::now() >= self.room.next_sync(),
+        };
+
+        if now {
+            self.sync();
+        } else {
+            self.room.sync_pending = true;
+        }
+    }
+
+    fn flush_pending(&mut self) {
+        if self.room.sync_pending {
+            self.sync();
+        }
+    }
+
+    /// Send a snapshot to the connections that dropped syncs, the pending changes are sent first to the others
+    fn resync_dropped(&mut self) {
+        let ids = self.room.dropped_syncs();
+        if ids.is_empty() {
+            return;
+        }
+
+        self.flush_pending();
+        for id in ids {
+            self.room.send_snapshot(&id, &*self.state);
+        }
+    }
+
+    /// Send the pending sync if it's due, returning when it should be sent otherwise
+    pub fn on_flush(&mut self) -> Option<Instant> {
+        self.on_flush_at(Instant::now())
+    }
+
+    fn on_flush_at(&mut self, now: Instant) -> Option<Instant> {
+        self.resync_dropped();
+
+        if !self.is_idle() || !self.room.sync_pending {
+            return None;
+        }
+
+        let next = self.room.next_sync();
+        if now >= next {
+            self.sync();
+            None
+        } else {
+            Some(next)
+        }
+    }
+
     fn sync(&mut self) {
-        println!("SYNC -> on container");
-        self.room.sync(&*self.state, &self.server);
+        self.room.sync_pending = false;
+        self.room.last_sync = Instant::now();
+
+        match self.state.take_changes() {
+            Some(changes) => self.room.sync_changes(&*self.state, changes),
+            None => self.room.sync(&*self.state, &self.server)
+        }
     }
 
-    pub fn on_connect(&mut self, id: &str) {
-        self.state.on_connect(id, &mut self.room, &mut self.server);
-        if let Some((c, _)) = self.room.connections.get(id) {
-            c.dispatch(ClientEvents::JoinRoom(self.room.id(), None));
+    pub fn on_connect(&mut self, id: &str, options: &JsonValue) {
+        let _running = RunningRoom::enter(&self.room.id);
+        self.state.on_connect(id, options, &mut self.room, &mut self.server);
+        if let Some(rc) = self.room.connections.get(id) {
+            rc.conn.dispatch(ClientEvents::JoinRoom(self.room.id(), None));
         }
+
+        //the new connection is skipped by the sync until it receives the snapshot
         self.sync();
+        self.room.send_snapshot(id, &*self.state);
     }
 
     pub fn on_init(&mut self) {
+        let _running = RunningRoom::enter(&self.room.id);
         self.state.on_init(&mut self.room, &mut self.server);
         self.room_state = ContainerState::Idle;
     }
 
+    /// Set as idle a container restored from a snapshot without calling State::on_init
+    pub fn on_restore(&mut self, max_connections: Option<usize>, tick_rate: Option<u32>, sync_policy: SyncPolicy) {
+        self.room.max_connections = max_connections;
+        self.room.sync_policy = sync_policy;
+        match tick_rate {
+            Some(rate) => self.room.set_tick_rate(rate),
+            None => self.room.disable_tick_rate()
+        }
+
+        self.room_state = ContainerState::Idle;
+    }
+
+    fn to_snapshot(&self) -> RoomSnapshot {
+        RoomSnapshot {
+            id: self.id(),
+            kind: self.kind.clone(),
+            state: self.state.to_json(),
+            max_connections: self.room.max_connections,
+            tick_rate: self.room.tick_rate,
+            sync_policy: self.room.sync_policy,
+        }
+    }
+
     pub fn on_destroy(&mut self) {
+        let _running = RunningRoom::enter(&self.room.id);
         self.state.on_destroy(&mut self.room, &mut self.server);
         self.room_state = ContainerState::Destroyed;
-        //todo clear connections
+
+        let id = self.id();
+        for (_, rc) in self.room.connections.drain() {
+            rc.conn.dispatch(ClientEvents::CloseRoom(id.clone(), "destroyed".to_string()));
+        }
     }
 
     pub fn on_broadcast(&mut self, msg: &Message) {
@@ -289,8 +599,9 @@ impl RoomContainer {
             return; 
         }
 
+        let _running = RunningRoom::enter(&self.room.id);
         self.state.on_broadcast(msg, &mut self.room, &mut self.server);
-        self.sync();
+        self.request_sync();
     }
 
     pub fn on_message(&mut self, conn_id: &str, msg: &Message) {
@@ -299,8 +610,62 @@ impl RoomContainer {
             return; 
         }
 
-        self.state.on_message(conn_id, msg, &mut self.room, &mut self.server);
-        self.sync();
+        let _running = RunningRoom::enter(&self.room.id);
+        match self.room.role(conn_id) {
+            Some(Role::Player) => self.state.on_message(conn_id, msg, &mut self.room, &mut self.server),
+            Some(Role::Spectator) => self.state.on_spectator_message(conn_id, msg, &mut self.room, &mut self.server),
+            None => {
+                println!("Connection {} can't send messages to {}:{} without joining it.", conn_id, self.kind, self.id());
+                return;
+            }
+        }
+
+        self.request_sync();
+    }
+
+    pub fn on_update(&mut self, delta: f32) {
+        if !self.is_idle() {
+            println!("Can't update container {}:{} because it's not idle yet.", self.kind, self.id());
+            return;
+        }
+
+        let _running = RunningRoom::enter(&self.room.id);
+        self.state.on_update(delta, &mut self.room, &mut self.server);
+        self.request_sync();
+    }
+
+    /// Update the state if the room's tick is due, returning when the next tick should happen
+    pub fn on_tick(&mut self) -> Option<Instant> {
+        if !self.is_idle() {
+            return None;
+        }
+
+        self.room.tick_step()?;
+
+        let now = Instant::now();
+        if now < self.room.next_tick {
+            return Some(self.room.next_tick);
+        }
+
+        let delta = now - self.room.last_tick;
+        self.room.last_tick = now;
+        self.on_update(delta.as_secs_f32());
+
+        let step = self.room.tick_step()?;
+        let end = Instant::now();
+        let spent = end - now;
+
+        //skip the missed ticks instead of trying to catch up to avoid a spiral of overruns
+        let mut next = self.room.next_tick + step;
+        if spent > step {
+            println!("Tick on container {}:{} overran its budget ({:?} > {:?}).", self.kind, self.id(), spent, step);
+            next = end + step;
+        } else if next <= end {
+            next = end + step;
+        }
+
+        self.room.next_tick = next;
+        Some(next)
     }
 
     pub fn is_idle(&self) -> bool {
@@ -308,6 +673,62 @@ impl RoomContainer {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct RoomSnapshot {
+    id: RoomId,
+    kind: String,
+    state: JsonValue,
+    max_connections: Option<usize>,
+    tick_rate: Option<u32>,
+    #[serde(default)]
+    sync_policy: SyncPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArenaSnapshot {
+    main_room: Option<RoomId>,
+    rooms: Vec<RoomSnapshot>,
+}
+
+pub type StateBuilder = Box<Fn(&JsonValue) -> Result<Box<State>, ArenaError> + Send + Sync>;
+
+/// Constructors of the states of each room kind, called with null to create a new room
+/// or with the json saved by `Arena::to_state` to restore one
+pub struct StateRegistry {
+    builders: HashMap<String, StateBuilder>
+}
+
+impl StateRegistry {
+    pub fn new() -> StateRegistry {
+        StateRegistry {
+            builders: HashMap::new()
+        }
+    }
+
+    pub fn register<F>(&mut self, kind: &str, builder: F) 
+        where F: Fn(&JsonValue) -> Result<Box<State>, ArenaError> + Send + Sync + 'static
+    {
+        self.builders.insert(kind.to_string(), Box::new(builder));
+    }
+
+    pub fn contains(&self, kind: &str) -> bool {
+        self.builders.contains_key(kind)
+    }
+
+    pub fn build(&self, kind: &str, state: &JsonValue) -> Result<Box<State>, ArenaError> {
+        match self.builders.get(kind) {
+            Some(builder) => builder(state),
+            None => Err(ArenaError::StateBuilderNotFound { kind: kind.to_string() })
+        }
+    }
+}
+
+impl std::fmt::Debug for StateRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "StateRegistry {{ kinds: {:?} }}", self.builders.keys().collect::<Vec<_>>())
+    }
+}
+
 #[derive(Debug)]
 struct ContainerList {
     list: HashMap<String, Vec<String>>,
@@ -322,18 +743,28 @@ impl ContainerList {
         }
     }
 
-    pub fn add(&mut self, name: &str, state: Box<State>, server: Arena) -> Result<String, String> {
+    pub fn add(&mut self, name: &str, state: Box<State>, server: Arena) -> Result<String, ArenaError> {
         let mut id = nanoid::simple();
         while self.containers.contains_key(&id) {
             id = nanoid::simple();
         }
 
+        self.insert(&id, name, state, server)?;
+
+        Ok(id.to_owned())
+    }
+
+    pub fn insert(&mut self, id: &str, name: &str, state: Box<State>, server: Arena) -> Result<(), ArenaError> {
+        if self.containers.contains_key(id) {
+            return Err(ArenaError::RoomAlreadyExists { id: id.to_string() });
+        }
+
         let list = self.list.entry(name.to_string()).or_insert(vec![]);
-        list.push(id.clone());
+        list.push(id.to_string());
 
-        self.containers.insert(id.clone(), Arc::new(Mutex::new(RoomContainer::new(&id, name, state, server))));
+        self.containers.insert(id.to_string(), Arc::new(Mutex::new(RoomContainer::new(id, name, state, server))));
 
-        Ok(id.to_owned())
+        Ok(())
     }
     
 
@@ -345,6 +776,17 @@ impl ContainerList {
         self.containers.get(id).map(|c| c.clone())
     }
 
+    /// Kind of a room without locking its container
+    pub fn kind_of(&self, id: &str) -> Option<String> {
+        self.list.iter()
+            .find(|(_, ids)| ids.iter().any(|v| v == id))
+            .map(|(kind, _)| kind.clone())
+    }
+
+    pub fn kinds(&self) -> Vec<String> {
+        self.list.keys().cloned().collect()
+    }
+
     pub fn get_ids_by_kind(&self, kind: &str) -> Option<Vec<String>> {
         match self.list.get(kind) {
             Some(list) => Some(list.to_vec()),
@@ -352,20 +794,21 @@ impl ContainerList {
         }
     }
 
-    pub fn remove(&mut self, id: &str) -> Result<Arc<Mutex<RoomContainer>>, String> {
+    pub fn remove(&mut self, id: &str) -> Result<Arc<Mutex<RoomContainer>>, ArenaError> {
         let container = self.containers.remove(id); 
         match container {
             Some(c) => {
-                let group = c.lock().kind.clone();
-                if let Some(list) = self.list.get_mut(&group) {
+                //the container is not locked to get the kind because the list could be locked from a state
+                for list in self.list.values_mut() {
                     if let Some(index) = list.iter().position(|v| *v == id) {
                         list.remove(index);
+                        break;
                     }
                 }
 
                 Ok(c)
             },
-            None => Err(format!("Invalid room id '{}'", id))
+            None => Err(ArenaError::RoomNotFound { id: id.to_string() })
         }
     }
 
@@ -387,6 +830,12 @@ pub struct Arena {
     main_room: Arc<RwLock<Option<RoomId>>>,
     list: Arc<RwLock<ContainerList>>,
     connections: Arc<RwLock<HashMap<ConnId, Connection>>>,
+    suspended: Arc<RwLock<HashMap<ConnId, Instant>>>,
+    reconnect_timeout: Arc<RwLock<Option<Duration>>>,
+    registry: Arc<RwLock<StateRegistry>>,
+    outbound_limit: Arc<RwLock<Option<OutboundLimit>>>,
+    matchmaker: Arc<RwLock<Option<Matchmaker>>>,
+    metrics: Metrics,
 
     in_recv: channel::Receiver<RoomEvents>,
     in_send: channel::Sender<RoomEvents>,
@@ -405,6 +854,12 @@ impl Arena {
             list: Arc::new(RwLock::new(ContainerList::new())),
 
             connections: Arc::new(RwLock::new(HashMap::new())),
+            suspended: Arc::new(RwLock::new(HashMap::new())),
+            reconnect_timeout: Arc::new(RwLock::new(None)),
+            registry: Arc::new(RwLock::new(StateRegistry::new())),
+            outbound_limit: Arc::new(RwLock::new(None)),
+            matchmaker: Arc::new(RwLock::new(None)),
+            metrics: Metrics::new(),
 
             in_recv: in_recv,
             in_send: in_send,
@@ -414,14 +869,43 @@ impl Arena {
         }
     }
 
-    pub fn from_state() -> Arena {
-        //todo create arena from a saved state
-        unimplemented!()
+    /// Create an arena from a snapshot made with to_state, the states are rebuilt using the registry.
+    /// The registry is kept by the arena to create the new rooms.
+    pub fn from_state(snapshot: &JsonValue, registry: StateRegistry) -> Result<Arena, ArenaError> {
+        let snapshot: ArenaSnapshot = serde_json::from_value(snapshot.clone())
+            .map_err(|e| ArenaError::InvalidSnapshot { reason: e.to_string() })?;
+
+        let mut s = Arena::new();
+        *s.registry.write() = registry;
+        for r in snapshot.rooms {
+            let state = s.build_state(&r.kind, &r.state)?;
+            s.list.write().insert(&r.id, &r.kind, state, s.clone())?;
+
+            println!("Restored room {}:{}", r.kind, r.id);
+
+            let opt_container = s.list.read().get(&r.id);
+            if let Some(c) = opt_container {
+                c.lock().on_restore(r.max_connections, r.tick_rate, r.sync_policy);
+            }
+        }
+
+        if let Some(id) = snapshot.main_room {
+            s.set_main_room(&id)?;
+        }
+
+        Ok(s)
     }
 
-    pub fn to_state() -> JsonValue {
-        //todo save the state of the server to a string or json value
-        unimplemented!()
+    /// Save the rooms of the arena to a json value, the connections are not saved
+    pub fn to_state(&self) -> JsonValue {
+        let rooms = self.containers().iter()
+            .map(|c| c.lock().to_snapshot())
+            .collect();
+
+        json!(ArenaSnapshot {
+            main_room: self.main_room(),
+            rooms: rooms
+        })
     }
 
     pub fn dispatch_to_client(&self, evt: ClientEvents) {
@@ -446,6 +930,14 @@ impl Arena {
         s
     }
 
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn get_room_kind(&self, id: &str) -> Option<String> {
+        self.list.read().kind_of(id)
+    }
+
     pub fn room_len(&self) -> usize {
         self.list.read().room_len()
     }
@@ -458,9 +950,14 @@ impl Arena {
         self.in_send.send(msg);
     }
 
-    pub fn new_conn(&mut self) -> Result<Connection, String> {
+    pub fn new_conn(&mut self) -> Result<Connection, ArenaError> {
+        self.new_conn_as(None)
+    }
+
+    /// Open a connection of an authenticated user, the identity can be checked by the states on `validate_connection`
+    pub fn new_conn_as(&mut self, identity: Option<Identity>) -> Result<Connection, ArenaError> {
         if self.main_room.read().is_none() {
-            return Err("Not found a main room.".to_string());
+            return Err(ArenaError::NoMainRoom);
         }
 
         let mut id = nanoid::simple();
@@ -468,15 +965,142 @@ impl Arena {
             id = nanoid::simple();
         }
 
-        let conn = Connection::with_id(&id);
+        let conn = Connection::with_identity(&id, identity);
+        if let Some(limit) = self.get_outbound_limit() {
+            conn.limit_outbound(limit, self.metrics.clone(), self.in_send.clone());
+        }
+
         self.connections.write().insert(id, conn.clone());
-        conn.dispatch(ClientEvents::OpenConnection(conn.id.clone()));
+        conn.dispatch(ClientEvents::OpenConnection(conn.id.clone(), conn.token.clone()));
 
         self.add_connection_to_main(conn.clone())?;
 
         Ok(conn)
     }
 
+    /// Time that a dropped connection keeps its id and rooms waiting for a resume, None to disable it
+    pub fn set_reconnect_timeout(&mut self, timeout: Option<Duration>) {
+        *self.reconnect_timeout.write() = timeout;
+    }
+
+    pub fn get_reconnect_timeout(&self) -> Option<Duration> {
+        *self.reconnect_timeout.read()
+    }
+
+    /// Matchmaker polled by run_ticks, the states can take it with `matchmaker` to enqueue tickets
+    pub fn set_matchmaker(&mut self, matchmaker: Option<Matchmaker>) {
+        *self.matchmaker.write() = matchmaker;
+    }
+
+    pub fn matchmaker(&self) -> Option<Matchmaker> {
+        self.matchmaker.read().clone()
+    }
+
+    /// Bound the queue of events of the connections opened after it, None to leave them unbounded
+    pub fn set_outbound_limit(&mut self, limit: Option<OutboundLimit>) {
+        *self.outbound_limit.write() = limit;
+    }
+
+    pub fn get_outbound_limit(&self) -> Option<OutboundLimit> {
+        *self.outbound_limit.read()
+    }
+
+    /// Keep a dropped connection alive until the reconnect timeout expires
+    pub fn suspend_connection(&mut self, conn_id: &str) {
+        let timeout = match self.get_reconnect_timeout() {
+            Some(t) if self.connections.read().contains_key(conn_id) => t,
+            _ => {
+                self.send(RoomEvents::CloseConnection(conn_id.to_string()));
+                return;
+            }
+        };
+
+        //it's closed by run_ticks once the deadline passes
+        self.suspended.write().insert(conn_id.to_string(), Instant::now() + timeout);
+        println!("Connection {} suspended for {:?}", conn_id, timeout);
+    }
+
+    /// Close the suspended connections whose reconnect timeout expired, returns the next deadline
+    fn expire_suspended(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let expired: Vec<ConnId> = {
+            let mut suspended = self.suspended.write();
+            let expired: Vec<ConnId> = suspended.iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            for id in &expired {
+                suspended.remove(id);
+            }
+
+            expired
+        };
+
+        for id in expired {
+            self.send(RoomEvents::CloseConnection(id));
+        }
+
+        self.suspended.read().values().min().cloned()
+    }
+
+    /// Take again a suspended connection using the token sent on the init event
+    pub fn resume_conn(&mut self, token: &str) -> Result<Connection, ArenaError> {
+        self.resume_conn_as(token, None)
+    }
+
+    /// Take again a suspended connection, it must belong to the same user
+    pub fn resume_conn_as(&mut self, token: &str, identity: Option<&Identity>) -> Result<Connection, ArenaError> {
+        let user_id = identity.map(|i| i.user_id.as_str());
+        let opt_conn = {
+            let connections = self.connections.read();
+            self.suspended.read().keys()
+                .filter_map(|id| connections.get(id))
+                .find(|c| c.token == token && c.user_id() == user_id)
+                .cloned()
+        };
+
+        let conn = opt_conn.ok_or(ArenaError::InvalidResumeToken)?;
+        self.suspended.write().remove(&conn.id);
+
+        //drop the events queued while it was disconnected, the rooms will send a snapshot
+        conn.reset_channel();
+        conn.dispatch(ClientEvents::OpenConnection(conn.id.clone(), conn.token.clone()));
+        self.send(RoomEvents::ResumeConnection(conn.id.clone()));
+
+        println!("Connection {} resumed", conn.id);
+
+        Ok(conn)
+    }
+
+    /// Open the connection of a client accepted by an adapter, resuming the suspended one of the token if possible.
+    /// The identity is the user authenticated by the adapter, None for anonymous clients.
+    pub fn accept(&mut self, resume_token: Option<&str>, identity: Option<Identity>) -> Result<Connection, ArenaError> {
+        match resume_token {
+            Some(token) => self.resume_conn_as(token, identity.as_ref())
+                .or_else(|e| {
+                    println!("Error resuming connection: {}", e);
+                    self.new_conn_as(identity)
+                }),
+            None => self.new_conn_as(identity)
+        }
+    }
+
+    /// Clone of an open connection, used to check its identity
+    pub fn get_connection(&self, conn_id: &str) -> Option<Connection> {
+        self.connections.read().get(conn_id).cloned()
+    }
+
+    /// Close the connection of a client when its transport is closed,
+    /// a dropped transport could be a network issue so the connection is suspended instead
+    pub fn disconnect(&mut self, conn_id: &str, dropped: bool) {
+        if dropped {
+            self.suspend_connection(conn_id);
+        } else {
+            self.send(RoomEvents::CloseConnection(conn_id.to_string()));
+        }
+    }
+
     pub fn run(&mut self) {
         use RoomEvents::*;
         
@@ -485,19 +1109,34 @@ impl Arena {
             //handle messages
             match msg {
                 CloseConnection(id) => {
+                    self.suspended.write().remove(&id);
                     self.remove_connection(&id);
                     if let Some(c) = self.connections.write().remove(&id) {
-                        c.dispatch(ClientEvents::CloseConnection(Some("".to_string())));
+                        c.close(Some("".to_string()));
                     }
                 },
-                JoinRoom(room_id, conn_id) => {
+                JoinRoom(room_id, conn_id, options) => {
                     let opt_conn = self.connections.read().get(&conn_id)
                         .map(|conn| conn.clone());
 
                     match opt_conn {
                         Some(conn) => { //TODO move to conn.in_send.send(-)
                             let c = conn.clone();
-                            if let Err(e) = self.add_connection_to(&room_id, conn) {
+                            if let Err(e) = self.add_connection_with_options(&room_id, conn, &options) {
+                                c.dispatch(ClientEvents::JoinRoom(room_id, Some(e)));
+                            }
+                        },
+                        None => {
+                            println!("Invalid connection id: {}", conn_id);
+                        }
+                    }
+                },
+                Spectate(room_id, conn_id, options) => {
+                    let opt_conn = self.connections.read().get(&conn_id).cloned();
+                    match opt_conn {
+                        Some(conn) => {
+                            let c = conn.clone();
+                            if let Err(e) = self.add_spectator_to(&room_id, conn, &options) {
                                 c.dispatch(ClientEvents::JoinRoom(room_id, Some(e)));
                             }
                         },
@@ -506,8 +1145,29 @@ impl Arena {
                         }
                     }
                 },
+                JoinOrCreate(kind, conn_id, options) => {
+                    let opt_conn = self.connections.read().get(&conn_id).cloned();
+                    if let Err(e) = self.join_or_create(&conn_id, &kind, &options) {
+                        println!("Error joining a room of kind {}: {}", kind, e);
+                        if let Some(c) = opt_conn {
+                            c.dispatch(ClientEvents::RejectJoinOrCreate(kind, e));
+                        }
+                    }
+                },
+                CloseRoom(room_id, conn_id) => {
+                    let opt_container = self.list.read().get(&room_id);
+                    match opt_container {
+                        Some(c) => {
+                            c.lock().remove_connection(&conn_id);
+                        },
+                        None => {
+                            println!("Invalid room id {} to close", room_id);
+                        }
+                    }
+                },
                 Broadcast(room_id, msg) => {
-                    match self.list.read().get(&room_id) {
+                    let opt_container = self.list.read().get(&room_id);
+                    match opt_container {
                         Some(c) => {
                             c.lock().on_broadcast(&msg);
                         },
@@ -517,7 +1177,8 @@ impl Arena {
                     }
                 },
                 Msg(room_id, conn_id, msg) => {
-                    match self.list.read().get(&room_id) {
+                    let opt_container = self.list.read().get(&room_id);
+                    match opt_container {
                         Some(c) => {
                             c.lock().on_message(&conn_id, &msg);
                         },
@@ -525,19 +1186,70 @@ impl Arena {
                             println!("Invalid room id {} to send message", room_id);
                         }
                     }
+                },
+                DestroyRoom(room_id) => {
+                    if let Err(e) = self.destroy_room(&room_id) {
+                        println!("Error destroying room: {}", e);
+                    }
+                },
+                ResumeConnection(conn_id) => {
+                    self.containers().par_iter()
+                        .for_each(|c| c.lock().resume_connection(&conn_id));
+                },
+                Resync(room_id, conn_id) => {
+                    let opt_container = self.list.read().get(&room_id);
+                    match opt_container {
+                        Some(c) => {
+                            c.lock().resync(&conn_id);
+                        },
+                        None => {
+                            println!("Invalid room id {} to resync", room_id);
+                        }
+                    }
                 }
                 _ => ()
             }
         }
     }
 
+    /// Loop that keeps calling on_update on the rooms with a tick rate, it also flushes the pending syncs,
+    /// closes the suspended connections that were not resumed in time and polls the matchmaker
+    pub fn run_ticks(&mut self) {
+        let max_wait = Duration::from_millis(MAX_TICK_WAIT_MS);
+
+        loop {
+            if let Some(mut matchmaker) = self.matchmaker() {
+                matchmaker.poll(self);
+            }
+
+            let rooms = self.containers().par_iter()
+                .filter_map(|c| {
+                    let mut container = c.lock();
+                    let tick = container.on_tick();
+                    let flush = container.on_flush();
+                    tick.into_iter().chain(flush).min()
+                })
+                .min();
+            let next = rooms.into_iter().chain(self.expire_suspended()).min();
+
+            let now = Instant::now();
+            let wait = match next {
+                Some(n) if n > now => (n - now).min(max_wait),
+                Some(_) => Duration::from_millis(0),
+                None => max_wait
+            };
+
+            thread::sleep(wait);
+        }
+    }
+
     pub fn main_room(&self) -> Option<String> {
         self.main_room.read().clone()
     }
 
-    pub fn set_main_room(&mut self, id: &str) -> Result<(), String> {
+    pub fn set_main_room(&mut self, id: &str) -> Result<(), ArenaError> {
         if !self.list.read().contains(id) {
-            Err(format!("Not found the room {}", id))
+            Err(ArenaError::RoomNotFound { id: id.to_string() })
         } else {
             println!("Setting as main_room {}", id);
             *self.main_room.write() = Some(id.to_string());
@@ -545,40 +1257,160 @@ impl Arena {
         }
     }
 
-    fn add_connection_to_main(&mut self, conn: Connection) -> Result<(), String> {
+    fn add_connection_to_main(&mut self, conn: Connection) -> Result<(), ArenaError> {
         let main = self.main_room.read().clone();
         match main {
             Some(m) => self.add_connection_to(&m, conn),
-            _ => Err("Not found a main room.".to_string())
+            _ => Err(ArenaError::NoMainRoom)
         }
     }
 
     pub fn remove_connection(&mut self, conn_id: &str) {
-        let list = self.list.read();
-        list.containers.par_iter()
-            .for_each(|(_, c)|{
+        self.containers().par_iter()
+            .for_each(|c|{
                 let mut container = c.lock();
                 container.remove_connection(conn_id);
             });
     }
 
-    pub fn add_connection_to(&mut self, id: &str, conn: Connection) -> Result<(), String> {
-        let opt_container = self.list.read().get(id);
-        match opt_container {
-            Some(c) => {
+    /// Clone the list of containers to avoid locking the list while a container is in use
+    fn containers(&self) -> Vec<Arc<Mutex<RoomContainer>>> {
+        self.list.read().containers.values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn add_connection_to(&mut self, id: &str, conn: Connection) -> Result<(), ArenaError> {
+        self.add_connection_with_options(id, conn, &JsonValue::Null)
+    }
+
+    /// Add a connection to a room with the options sent by the client when joining, like a password or a team
+    pub fn add_connection_with_options(&mut self, id: &str, conn: Connection, options: &JsonValue) -> Result<(), ArenaError> {
+        let opt_container = self.list.read().get(id);
+        match opt_container {
+            Some(c) => {
+                let mut container = c.lock();
+
+                let id = conn.id.clone();
+                container.add_connection(conn, options)?;
+                container.on_connect(&id, options);
+
+                Ok(())
+            },
+            _ => Err(ArenaError::RoomNotFound { id: id.to_string() })
+        }
+    }
+
+    /// Move a group of connections to a room at once, if one of them can't join none of them is added
+    pub fn add_connections_to(&mut self, id: &str, conn_ids: &[ConnId]) -> Result<(), ArenaError> {
+        let conns = {
+            let connections = self.connections.read();
+            let mut conns = vec![];
+            for conn_id in conn_ids {
+                match connections.get(conn_id) {
+                    Some(c) => conns.push(c.clone()),
+                    None => return Err(ArenaError::InvalidConnection { id: conn_id.to_string() })
+                }
+            }
+            conns
+        };
+
+        let opt_container = self.list.read().get(id);
+        match opt_container {
+            Some(c) => {
+                let mut container = c.lock();
+                let added = container.add_connections(conns)?;
+                for conn_id in &added {
+                    container.on_connect(conn_id, &JsonValue::Null);
+                }
+
+                Ok(())
+            },
+            _ => Err(ArenaError::RoomNotFound { id: id.to_string() })
+        }
+    }
+
+    /// Constructor of the states of a kind, used by join_or_create, the matchmaker and from_state.
+    /// It's called with null for a new room.
+    pub fn register_state<F>(&mut self, kind: &str, builder: F)
+        where F: Fn(&JsonValue) -> Result<Box<State>, ArenaError> + Send + Sync + 'static
+    {
+        self.registry.write().register(kind, builder);
+    }
+
+    /// Build a state of a kind with its registered constructor, null for a new room
+    pub fn build_state(&self, kind: &str, state: &JsonValue) -> Result<Box<State>, ArenaError> {
+        self.registry.read().build(kind, state)
+    }
+
+    /// Add a connection to a room as spectator, it receives the syncs but it doesn't play
+    pub fn add_spectator_to(&mut self, id: &str, conn: Connection, options: &JsonValue) -> Result<(), ArenaError> {
+        let opt_container = self.list.read().get(id);
+        match opt_container {
+            Some(c) => {
                 let mut container = c.lock();
 
                 let id = conn.id.clone();
-                container.add_connection(conn)?;
-                container.on_connect(&id);
+                container.add_spectator(conn, options)?;
+                container.on_connect(&id, options);
 
                 Ok(())
             },
-            _ => Err(format!("Room {} doesn't exists.", id))
+            _ => Err(ArenaError::RoomNotFound { id: id.to_string() })
         }
     }
 
-    pub fn add(&mut self, name: &str, state: Box<State>) -> Result<String, String> {
+    /// Add a connection to the first room of the kind that accepts it, or to a new one built with the factory of the kind.
+    /// Returns the id of the room joined.
+    ///
+    /// The rooms running the callback that calls it are skipped, it waits for the other rooms of the kind.
+    /// Calling it from the updates of two rooms of the kind at the same time can block both of them.
+    pub fn join_or_create(&mut self, conn_id: &str, kind: &str, options: &JsonValue) -> Result<RoomId, ArenaError> {
+        let conn = self.get_connection(conn_id)
+            .ok_or(ArenaError::InvalidConnection { id: conn_id.to_string() })?;
+
+        let ids = self.list.read().get_ids_by_kind(kind).unwrap_or_default();
+        for id in ids {
+            //locked by this thread, it would never be released
+            if RunningRoom::contains(&id) {
+                continue;
+            }
+
+            let c = match self.list.read().get(&id) {
+                Some(c) => c,
+                None => continue
+            };
+
+            let mut container = c.lock();
+            if container.room.connections.contains_key(conn_id) {
+                continue;
+            }
+
+            //full, starting or rejected by the state, try the next one
+            if container.add_connection(conn.clone(), options).is_ok() {
+                container.on_connect(conn_id, options);
+                return Ok(container.id());
+            }
+        }
+
+        let state = self.build_state(kind, &JsonValue::Null)?;
+
+        println!("Not found a room of kind {} for {}, creating a new one...", kind, conn_id);
+        let id = self.add(kind, state)?;
+        if let Err(e) = self.add_connection_with_options(&id, conn, options) {
+            //a room that rejects its first connection would stay empty
+            self.remove(&id)?;
+            return Err(e);
+        }
+
+        Ok(id)
+    }
+
+    pub fn has_connection(&self, conn_id: &str) -> bool {
+        self.connections.read().contains_key(conn_id)
+    }
+
+    pub fn add(&mut self, name: &str, state: Box<State>) -> Result<String, ArenaError> {
         let s = self.clone();
         let id = self.list.write().add(name, state, s)?;
 
@@ -593,10 +1425,24 @@ impl Arena {
         Ok(id)
     }
 
-    pub fn remove(&mut self, id: &str) -> Result<(), String> {
-        //TODO FIXME deadlocks every time
+    /// Request the destruction of a room, it's done by the run loop once the current handler releases its locks
+    pub fn remove(&mut self, id: &str) -> Result<(), ArenaError> {
+        if !self.list.read().contains(id) {
+            return Err(ArenaError::RoomNotFound { id: id.to_string() });
+        }
+
+        self.send(RoomEvents::DestroyRoom(id.to_string()));
+        Ok(())
+    }
+
+    fn destroy_room(&mut self, id: &str) -> Result<(), ArenaError> {
         let container = self.list.write().remove(id)?;
-        //container.lock().on_destroy(); //todo fixme deadlock if it's called from a event on state
+        container.lock().on_destroy();
+
+        let mut main_room = self.main_room.write();
+        if main_room.as_ref().map_or(false, |main| main == id) {
+            *main_room = None;
+        }
 
         println!("Removed room {}", id);
 
@@ -607,6 +1453,18 @@ impl Arena {
         self.list.read().get_ids_by_kind(kind)
     }
 
+    pub fn kinds(&self) -> Vec<String> {
+        self.list.read().kinds()
+    }
+
+    pub fn get_room(&self, id: &str) -> Option<Arc<Mutex<RoomContainer>>> {
+        self.list.read().get(id)
+    }
+
+    pub fn connection_ids(&self) -> Vec<ConnId> {
+        self.connections.read().keys().cloned().collect()
+    }
+
     pub fn get_rooms_by_kind(&self, kind: &str) -> Vec<Arc<Mutex<RoomContainer>>> {
         let list = self.list.read();
         let mut rooms = vec![];
@@ -638,14 +1496,111 @@ impl Arena {
     }
 }
 
+/// When the changes of a state are sent, the changes made between two syncs are merged in one patch
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    /// After every message, broadcast or update
+    Immediate,
+    /// At most once per interval, the changes always wait for the end of the interval
+    Interval(Duration),
+    /// At most this amount of times per second, a change is sent at once if the last sync is old enough
+    MaxRate(u32),
+}
+
+impl Default for SyncPolicy {
+    fn default() -> SyncPolicy {
+        SyncPolicy::Immediate
+    }
+}
+
+/// How a connection takes part in a room
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Player,
+    /// Receives the syncs but doesn't count toward max_connections, its messages go to State::on_spectator_message
+    Spectator,
+}
+
+/// Connection inside a room with the states already sent to it
+#[derive(Debug)]
+struct RoomConnection {
+    conn: Connection,
+    role: Role,
+    states: Vec<JsonValue>,
+    version: u64,
+    seq: u64,
+}
+
+impl RoomConnection {
+    fn new(conn: Connection, role: Role) -> RoomConnection {
+        RoomConnection {
+            conn: conn,
+            role: role,
+            states: vec![],
+            version: 0,
+            seq: 0,
+        }
+    }
+
+    /// State synced to the connection, the spectators can have their own view
+    fn view(&self, conn_id: &str, state: &State) -> JsonValue {
+        match self.role {
+            Role::Player => state.to_sync(conn_id),
+            Role::Spectator => state.to_spectator_sync(conn_id),
+        }
+    }
+
+    fn view_change(&self, conn_id: &str, state: &State, change: &Change) -> Option<Change> {
+        match self.role {
+            Role::Player => state.sync_change(change, conn_id),
+            Role::Spectator => state.spectator_sync_change(change, conn_id),
+        }
+    }
+
+    /// Check that the client keeps the same view than to_sync applying the patch, only on the tests
+    #[cfg(test)]
+    fn check_patch(&mut self, conn_id: &str, state: &State, patch: &[JsonValue]) {
+        let mut last = match self.states.pop() {
+            Some(last) => last,
+            None => return
+        };
+
+        let view = self.view(conn_id, state);
+        let r_patch = serde_json::from_value(json!(patch))
+            .map_err(|e| e.to_string())
+            .and_then(|p| json_patch::patch(&mut last, &p).map_err(|e| e.to_string()));
+
+        assert!(r_patch.is_ok() && last == view, "The changes sent to {} don't match its view, sync_change must hide \
+            the same than to_sync. Patched: {} View: {} Error: {:?}", conn_id, last, view, r_patch.err());
+        self.states.push(view);
+    }
+
+    /// Sequence number of the next sync message, used by the clients to detect missing messages
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+}
+
 #[derive(Debug)]
 pub struct Room {
     id: String,
     kind: String,
     max_connections: Option<usize>,
-    connections: HashMap<String, (Connection, Vec<JsonValue>)>,
+    connections: HashMap<String, RoomConnection>,
     states: Vec<JsonValue>,
-    state_limit: usize
+    state_limit: usize,
+    version: u64,
+    tick_rate: Option<u32>,
+    last_tick: Instant,
+    next_tick: Instant,
+    /// The last syncs were recorded changes, the states kept to diff are outdated
+    tracked: bool,
+    sync_policy: SyncPolicy,
+    sync_pending: bool,
+    last_sync: Instant,
 }
 
 impl Room {
@@ -661,6 +1616,57 @@ impl Room {
             connections: HashMap::new(),
             states: vec![state],
             state_limit: state_limit,
+            version: 0,
+            tick_rate: None,
+            last_tick: Instant::now(),
+            next_tick: Instant::now(),
+            tracked: false,
+            sync_policy: SyncPolicy::Immediate,
+            sync_pending: false,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Set how many times per second State::on_update will be called
+    pub fn set_tick_rate(&mut self, rate: u32) {
+        if rate == 0 {
+            self.disable_tick_rate();
+            return;
+        }
+
+        self.tick_rate = Some(rate);
+        self.last_tick = Instant::now();
+        self.next_tick = self.last_tick + Duration::from_nanos(1_000_000_000 / rate as u64);
+    }
+
+    pub fn disable_tick_rate(&mut self) {
+        self.tick_rate = None;
+    }
+
+    pub fn get_tick_rate(&self) -> Option<u32> {
+        self.tick_rate
+    }
+
+    fn tick_step(&self) -> Option<Duration> {
+        self.tick_rate.map(|rate| Duration::from_nanos(1_000_000_000 / rate as u64))
+    }
+
+    /// When the changes of the state are sent to the connections
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    pub fn get_sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Time when the next sync can be sent
+    fn next_sync(&self) -> Instant {
+        match self.sync_policy {
+            SyncPolicy::Immediate => self.last_sync,
+            SyncPolicy::Interval(interval) => self.last_sync + interval,
+            SyncPolicy::MaxRate(0) => self.last_sync,
+            SyncPolicy::MaxRate(rate) => self.last_sync + Duration::from_nanos(1_000_000_000 / rate as u64),
         }
     }
 
@@ -676,31 +1682,78 @@ impl Room {
         self.max_connections
     }
 
+    /// Only the players count toward max_connections
     pub fn is_full(&self) -> bool {
         if let Some(m) = self.max_connections {
-            self.connections.len() >= m
+            self.players_len() >= m
         } else {
             false
         }
     }
 
+    /// Players and spectators of the room
     pub fn connections_len(&self) -> usize {
         self.connections.len()
     } 
 
-    fn add_conn(&mut self, conn: Connection, state: &State) -> Result<(), String> {
-        state.validate_connection(&conn)?;
+    pub fn players_len(&self) -> usize {
+        self.connections.values().filter(|rc| rc.role == Role::Player).count()
+    }
 
-        println!("connection {} added on room: {}:{}", conn.id, self.kind, self.id);
- 
-        let id = conn.id.clone();
-        self.connections.insert(id, (conn, vec![]));
+    pub fn spectators_len(&self) -> usize {
+        self.connections.len() - self.players_len()
+    }
+
+    pub fn connection_ids(&self) -> Vec<ConnId> {
+        self.connections.keys().cloned().collect()
+    }
+
+    pub fn ids_by_role(&self, role: Role) -> Vec<ConnId> {
+        self.connections.iter()
+            .filter(|(_, rc)| rc.role == role)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    pub fn role(&self, conn_id: &str) -> Option<Role> {
+        self.connections.get(conn_id).map(|rc| rc.role)
+    }
+
+    /// Change the role of a connection, like a spectator taking a free seat
+    pub fn set_role(&mut self, conn_id: &str, role: Role) -> Result<(), ArenaError> {
+        if role == Role::Player && self.role(conn_id) == Some(Role::Spectator) && self.is_full() {
+            return Err(ArenaError::RoomFull { id: self.id() });
+        }
+
+        match self.connections.get_mut(conn_id) {
+            Some(rc) => {
+                rc.role = role;
+                Ok(())
+            },
+            None => Err(ArenaError::InvalidConnection { id: conn_id.to_string() })
+        }
+    }
+
+    fn add_conn(&mut self, conn: Connection, options: &JsonValue, state: &State) -> Result<(), ArenaError> {
+        state.validate_connection(&conn, options)?;
+        self.insert_conn(conn, Role::Player);
 
         Ok(())
     }
 
+    fn insert_conn(&mut self, conn: Connection, role: Role) {
+        println!("connection {} added on room as {:?}: {}:{}", conn.id, role, self.kind, self.id);
+ 
+        let id = conn.id.clone();
+        self.connections.insert(id, RoomConnection::new(conn, role));
+    }
+
     pub fn get_conn(&mut self, id: &str) -> Option<&mut Connection> {
-        self.connections.get_mut(id).map(|(c, _)| c)
+        self.connections.get_mut(id).map(|rc| &mut rc.conn)
+    }
+
+    pub fn metadata(&self, conn_id: &str) -> Option<&Metadata> {
+        self.connections.get(conn_id).map(|rc| rc.conn.metadata())
     }
 
     fn remove_conn(&mut self, id: &str) {
@@ -715,7 +1768,36 @@ impl Room {
         self.id.clone()
     }
 
+    /// Version of the state, it increases every time that the state changes
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Send the whole state to a connection, used when the connection can't apply a patch
+    pub fn send_snapshot(&mut self, conn_id: &str, state: &State) {
+        if let Some(rc) = self.connections.get_mut(conn_id) {
+            let data = rc.view(conn_id, state);
+            let msg = json!({
+                "seq": rc.next_seq(),
+                "version": self.version,
+                "state": &data
+            });
+
+            rc.conn.dispatch(ClientEvents::Msg(self.id.clone(), Message::new("snapshot", &msg)));
+            rc.conn.clear_resync(&self.id);
+
+            rc.states.clear();
+            rc.states.push(data);
+            rc.version = self.version;
+        }
+    }
+
     pub fn sync(&mut self, state: &State, server: &Arena) {
+        if self.tracked {
+            self.resync_all(state);
+            return;
+        }
+
         let empty_json = json!({});
         let current = state.to_json();
         let changes = diff(self.states.last().unwrap_or(&empty_json), &current);
@@ -733,39 +1815,218 @@ impl Room {
         }
 
         self.states.push(current);
+        self.version += 1;
 
         let limit = self.state_limit;
+        let version = self.version;
         let my_id = &self.id;
         self.connections.par_iter_mut()
-            .for_each(move |(id, (conn, conn_states))| {
-                let data = state.to_sync(id);
-                let diff = diff(conn_states.last().unwrap_or(&empty_json), &data);
+            .for_each(move |(id, rc)| {
+                //connections without a snapshot will receive the whole state later
+                let last = match rc.states.last() {
+                    Some(last) if !rc.conn.needs_resync(my_id) => last,
+                    _ => return
+                };
+
+                let data = rc.view(id, state);
+                let diff = diff(last, &data);
 
                 match diff {
                     json_patch::Patch(changes) => {
                         if changes.len() != 0 {
-                            conn_states.push(data);
+                            rc.states.push(data);
 
-                            if conn_states.len() >= limit {
-                                conn_states.remove(0);
+                            if rc.states.len() >= limit {
+                                rc.states.remove(0);
                             }
 
-                            let json_changes = json!(changes);
-                            println!("# Syncronizating {} - state {}", id, &json_changes);
-                            conn.dispatch(ClientEvents::Msg(my_id.to_string(), Message::new("sync", &json_changes)));
+                            let json_changes = json!({
+                                "seq": rc.next_seq(),
+                                "from_version": rc.version,
+                                "to_version": version,
+                                "patch": changes
+                            });
+
+                            rc.version = version;
+                            rc.conn.dispatch(ClientEvents::Msg(my_id.to_string(), Message::new("sync", &json_changes)));
                         }
                     }
                 }
             });
     }
+
+    /// Send the changes recorded by the state, filtered for the role of every connection, without diffing the whole state
+    pub fn sync_changes(&mut self, state: &State, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        self.tracked = true;
+        self.version += 1;
+
+        let version = self.version;
+        let my_id = &self.id;
+        let changes = &changes;
+        self.connections.par_iter_mut()
+            .for_each(move |(id, rc)| {
+                //connections without a snapshot will receive the whole state later
+                if rc.states.is_empty() || rc.conn.needs_resync(my_id) {
+                    return;
+                }
+
+                let patch: Vec<JsonValue> = changes.iter()
+                    .filter_map(|c| rc.view_change(id, state, c))
+                    .map(|c| c.to_json())
+                    .collect();
+
+                #[cfg(test)]
+                rc.check_patch(id, state, &patch);
+
+                if patch.is_empty() {
+                    return;
+                }
+
+                let json_changes = json!({
+                    "seq": rc.next_seq(),
+                    "from_version": rc.version,
+                    "to_version": version,
+                    "patch": patch
+                });
+
+                rc.version = version;
+                rc.conn.dispatch(ClientEvents::Msg(my_id.to_string(), Message::new("sync", &json_changes)));
+            });
+    }
+
+    /// Connections that dropped syncs of the room because their queue was full
+    fn dropped_syncs(&self) -> Vec<ConnId> {
+        self.connections.iter()
+            .filter(|(_, rc)| rc.conn.needs_resync(&self.id))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Send a snapshot to every connection, used when the states to diff are outdated
+    fn resync_all(&mut self, state: &State) {
+        let current = state.to_json();
+        self.states.clear();
+        self.states.push(current);
+        self.version += 1;
+        self.tracked = false;
+
+        let ids: Vec<ConnId> = self.connections.iter()
+            .filter(|(_, rc)| !rc.states.is_empty())
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in ids {
+            self.send_snapshot(&id, state);
+        }
+    }
+}
+
+
+/// Queue of events for the client and the callback used to wake up who is listening it
+struct ConnChannel {
+    send: channel::Sender<ClientEvents>,
+    recv: channel::Receiver<ClientEvents>,
+    notify: Option<Box<Fn() + Send + Sync>>,
+    /// Rooms that dropped syncs of the connection and must send it a snapshot
+    resync: HashSet<RoomId>,
+    /// The close was queued, the events are not queued anymore
+    closing: bool,
+}
+
+impl ConnChannel {
+    fn new() -> ConnChannel {
+        let (send, recv) = channel::unbounded();
+        ConnChannel {
+            send: send,
+            recv: recv,
+            notify: None,
+            resync: HashSet::new(),
+            closing: false,
+        }
+    }
+
+    fn drain(&self) -> Vec<ClientEvents> {
+        let mut events = vec![];
+        while let Some(evt) = self.recv.try_recv() {
+            events.push(evt);
+        }
+
+        events
+    }
+
+    fn requeue(&self, events: Vec<ClientEvents>) {
+        for evt in events {
+            self.send.send(evt);
+        }
+    }
+
+    fn close(&mut self, reason: Option<String>) {
+        self.closing = true;
+        self.send.send(ClientEvents::CloseConnection(reason));
+        if let Some(notify) = &self.notify {
+            notify();
+        }
+    }
+}
+
+impl std::fmt::Debug for ConnChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ConnChannel {{ pending: {} }}", self.recv.len())
+    }
 }
 
+/// Limit of the outbound queue of a connection, with what is needed to apply its policy
+#[derive(Clone)]
+struct QueueLimit {
+    limit: OutboundLimit,
+    metrics: Metrics,
+    /// Events of the arena, used to close the connection
+    arena: channel::Sender<RoomEvents>,
+}
+
+impl std::fmt::Debug for QueueLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "QueueLimit {{ {:?} }}", self.limit)
+    }
+}
+
+/// User authenticated by the adapter that accepted a connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub user_id: String,
+    /// Extra data given by the authenticator like the roles or the display name
+    pub claims: JsonValue,
+}
+
+impl Identity {
+    pub fn new(user_id: &str) -> Identity {
+        Identity::with_claims(user_id, JsonValue::Null)
+    }
+
+    pub fn with_claims(user_id: &str, claims: JsonValue) -> Identity {
+        Identity {
+            user_id: user_id.to_string(),
+            claims: claims,
+        }
+    }
+
+    pub fn claim(&self, name: &str) -> Option<&JsonValue> {
+        self.claims.get(name)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Connection {
     pub id: String,
-    in_recv: channel::Receiver<ClientEvents>,
-    in_send: channel::Sender<ClientEvents>
+    token: String,
+    identity: Option<Identity>,
+    metadata: Metadata,
+    channel: Arc<RwLock<ConnChannel>>,
+    limit: Arc<RwLock<Option<QueueLimit>>>,
 }
 
 impl Connection {
@@ -774,20 +2035,158 @@ impl Connection {
     }
 
     pub fn with_id(id: &str) -> Connection {
-        let (in_send, in_recv) = channel::unbounded();
         Connection {
             id: id.to_string(),
-            in_recv: in_recv,
-            in_send: in_send
+            token: nanoid::generate(32),
+            identity: None,
+            metadata: Metadata::new(),
+            channel: Arc::new(RwLock::new(ConnChannel::new())),
+            limit: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn with_identity(id: &str, identity: Option<Identity>) -> Connection {
+        Connection {
+            identity: identity,
+            ..Connection::with_id(id)
         }
     }
 
-    pub fn listen(&self) -> &channel::Receiver<ClientEvents> {
-        &self.in_recv
+    /// User of the connection, None for anonymous connections
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.identity.as_ref().map(|i| i.user_id.as_str())
+    }
+
+    /// Attributes of the connection, the changes are seen by the arena and all the rooms
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Token used by the client to resume this connection after a disconnection
+    pub fn token(&self) -> String {
+        self.token.clone()
+    }
+
+    /// Receiver of the events for the client, it ends when the channel is reset
+    pub fn listen(&self) -> channel::Receiver<ClientEvents> {
+        self.channel.read().recv.clone()
+    }
+
+    /// Set a callback called after every event dispatched, used to listen without blocking a thread.
+    /// It's dropped when the channel is reset or when all the clones of the connection are dropped.
+    pub fn on_dispatch<F>(&self, notify: F) 
+        where F: Fn() + Send + Sync + 'static
+    {
+        self.channel.write().notify = Some(Box::new(notify));
+    }
+
+    /// Events waiting to be delivered to the client
+    pub fn pending(&self) -> usize {
+        self.channel.read().recv.len()
+    }
+
+    /// The close is queued, the events the transport can't take anymore can be dropped
+    pub fn is_closing(&self) -> bool {
+        self.channel.read().closing
+    }
+
+    fn limit_outbound(&self, limit: OutboundLimit, metrics: Metrics, arena: channel::Sender<RoomEvents>) {
+        *self.limit.write() = Some(QueueLimit {
+            limit: limit,
+            metrics: metrics,
+            arena: arena,
+        });
+    }
+
+    fn reset_channel(&self) {
+        *self.channel.write() = ConnChannel::new();
+    }
+
+    /// The room dropped syncs of this connection and must send it a snapshot
+    fn needs_resync(&self, room_id: &str) -> bool {
+        self.channel.read().resync.contains(room_id)
+    }
+
+    fn clear_resync(&self, room_id: &str) {
+        if self.needs_resync(room_id) {
+            self.channel.write().resync.remove(room_id);
+        }
     }
 
     fn dispatch(&self, evt:ClientEvents) {
-        self.in_send.send(evt);
+        let limit = self.limit.read().clone();
+        if let Some(limit) = &limit {
+            if self.channel.read().recv.len() >= limit.limit.capacity {
+                self.on_full_queue(limit);
+            }
+        }
+
+        let channel = self.channel.read();
+        if channel.closing {
+            return;
+        }
+
+        channel.send.send(evt);
+        if let Some(limit) = &limit {
+            limit.metrics.record_pending(channel.recv.len());
+        }
+
+        if let Some(notify) = &channel.notify {
+            notify();
+        }
+    }
+
+    /// Make room in the queue with the policy of the limit
+    fn on_full_queue(&self, limit: &QueueLimit) {
+        let mut channel = self.channel.write();
+        //another room could have made room meanwhile
+        if channel.closing || channel.recv.len() < limit.limit.capacity {
+            return;
+        }
+
+        let events = channel.drain();
+        let len = events.len();
+        let kept = match limit.limit.policy {
+            OutboundPolicy::DropSyncs => {
+                let (kept, rooms) = outbound::drop_syncs(events);
+                limit.metrics.record_dropped_syncs(len - kept.len());
+                channel.resync.extend(rooms);
+                kept
+            },
+            OutboundPolicy::Coalesce => {
+                let (kept, merged) = outbound::coalesce(events);
+                limit.metrics.record_coalesced_syncs(merged);
+                kept
+            },
+            OutboundPolicy::Disconnect => events
+        };
+
+        //a pass that frees less than half of the queue would run again after a few events
+        let full = kept.len() > limit.limit.capacity / 2;
+        channel.requeue(kept);
+        if limit.limit.policy == OutboundPolicy::Disconnect || full {
+            self.close_channel(&mut channel, limit);
+        }
+    }
+
+    /// Queue the close as the last event of the connection
+    fn close(&self, reason: Option<String>) {
+        let mut channel = self.channel.write();
+        if !channel.closing {
+            channel.close(reason);
+        }
+    }
+
+    /// Stop queueing the events and ask the arena to close the connection
+    fn close_channel(&self, channel: &mut ConnChannel, limit: &QueueLimit) {
+        println!("Closing connection {} with {} pending events", self.id, channel.recv.len());
+        channel.close(Some("Too many pending events.".to_string()));
+        limit.metrics.record_disconnected();
+        limit.arena.send(RoomEvents::CloseConnection(self.id.clone()));
     }
 }
 
@@ -807,15 +2206,21 @@ pub trait State: Downcast + Send + Sync + std::fmt::Debug {
         println!("on message {}:{} conn: {} msg: {:?}", room.kind(), room.id(), conn_id, msg);
     }
 
+    /// Messages sent by the spectators, they are ignored by default
+    fn on_spectator_message(&mut self, conn_id: &str, msg: &Message, room: &mut Room, _server: &mut Arena) {
+        println!("Ignored message of spectator {} on {}:{} msg: {:?}", conn_id, room.kind(), room.id(), msg);
+    }
+
     fn on_broadcast(&mut self, msg: &Message, room: &mut Room, _server: &mut Arena) {
         println!("on broadcast {}:{} msg: {:?}", room.kind(), room.id(), msg);
     }
 
-    fn on_update(&mut self, room: &mut Room, _server: &mut Arena) {
-        println!("on update {}:{}", room.kind(), room.id());
+    fn on_update(&mut self, delta: f32, room: &mut Room, _server: &mut Arena) {
+        println!("on update {}:{} delta: {}", room.kind(), room.id(), delta);
     }
 
-    fn on_connect(&mut self, connection_id: &str, room: &mut Room, _server: &mut Arena) {
+    /// The options are sent by the client when joining, Null if the connection was added by the server
+    fn on_connect(&mut self, connection_id: &str, _options: &JsonValue, room: &mut Room, _server: &mut Arena) {
         println!("on connect [{}] {}:{}", connection_id, room.kind(), room.id());
     }
 
@@ -823,12 +2228,55 @@ pub trait State: Downcast + Send + Sync + std::fmt::Debug {
         println!("on connect [{}] {}:{}", connection_id, room.kind(), room.id());
     }
 
-    fn validate_connection(&self, _connection: &Connection) -> Result<(), String> {
+    /// Check if a connection can join the room with its options, like a password of a private room
+    fn validate_connection(&self, _connection: &Connection, _options: &JsonValue) -> Result<(), ArenaError> {
+        Ok(())
+    }
+
+    /// Check if a connection can watch the room
+    fn validate_spectator(&self, _connection: &Connection, _options: &JsonValue) -> Result<(), ArenaError> {
         Ok(())
     }
 
-    fn to_sync(&self, _conn_id: &str) -> JsonValue {
-        self.to_json()
+    /// Rules used by the default to_sync to hide parts of the state to some connections
+    fn visibility(&self) -> Option<&Visibility> {
+        None
+    }
+
+    /// Mutations recorded since the last sync, the syncs only send them instead of diffing the json of every connection.
+    /// None to find the changes with the diff, a state that records them must record all of them.
+    ///
+    /// The changes are filtered for every connection with sync_change, a state with a custom to_sync or
+    /// to_spectator_sync must filter them the same way, otherwise the clients will see what to_sync hides.
+    fn take_changes(&mut self) -> Option<Vec<Change>> {
+        None
+    }
+
+    /// Recorded change as seen by a connection, None to hide it. By default filtered by the visibility rules
+    fn sync_change(&self, change: &Change, conn_id: &str) -> Option<Change> {
+        match self.visibility() {
+            Some(visibility) => visibility.filter_change(change, conn_id),
+            None => Some(change.clone())
+        }
+    }
+
+    /// Recorded change as seen by a spectator
+    fn spectator_sync_change(&self, change: &Change, conn_id: &str) -> Option<Change> {
+        self.sync_change(change, conn_id)
+    }
+
+    /// State sent to a connection, by default the json filtered by the visibility rules.
+    /// The states deriving `SyncView` can return `self.sync_view(conn_id, None)` instead.
+    fn to_sync(&self, conn_id: &str) -> JsonValue {
+        match self.visibility() {
+            Some(visibility) => visibility.filter(&self.to_json(), conn_id),
+            None => self.to_json()
+        }
+    }
+
+    /// State sent to the spectators, it can hide what only the players should see
+    fn to_spectator_sync(&self, conn_id: &str) -> JsonValue {
+        self.to_sync(conn_id)
     }
 }
 
@@ -841,4 +2289,465 @@ impl State for EmptyState {
     fn to_json(&self) -> JsonValue {
         json!(self)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed_connections(arena: &Arena) -> Vec<ConnId> {
+        let mut closed = vec![];
+        while let Some(evt) = arena.in_recv.try_recv() {
+            if let RoomEvents::CloseConnection(id) = evt {
+                closed.push(id);
+            }
+        }
+
+        closed
+    }
+
+    #[test]
+    fn expire_suspended_closes_the_connections_after_the_timeout() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.set_reconnect_timeout(Some(Duration::from_millis(20)));
+        let conn = arena.new_conn().unwrap();
+
+        arena.suspend_connection(&conn.id);
+        assert!(arena.expire_suspended().is_some());
+        assert!(closed_connections(&arena).is_empty());
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(arena.expire_suspended(), None);
+        assert_eq!(closed_connections(&arena), vec![conn.id.clone()]);
+    }
+
+    #[test]
+    fn resume_conn_as_checks_the_user_of_the_connection() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.set_reconnect_timeout(Some(Duration::from_secs(10)));
+        let conn = arena.new_conn_as(Some(Identity::new("guest-a"))).unwrap();
+        arena.suspend_connection(&conn.id);
+
+        assert!(arena.resume_conn_as(&conn.token(), Some(&Identity::new("guest-b"))).is_err());
+        assert!(arena.resume_conn_as(&conn.token(), None).is_err());
+        assert_eq!(arena.resume_conn_as(&conn.token(), Some(&Identity::new("guest-a"))).unwrap().id, conn.id);
+    }
+
+    #[test]
+    fn resume_conn_as_resumes_the_anonymous_connections_with_the_token() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.set_reconnect_timeout(Some(Duration::from_secs(10)));
+        let conn = arena.new_conn_as(None).unwrap();
+        arena.suspend_connection(&conn.id);
+
+        assert!(arena.resume_conn_as(&conn.token(), Some(&Identity::new("anna"))).is_err());
+        assert_eq!(arena.accept(Some(&conn.token()), None).unwrap().id, conn.id);
+    }
+
+    /// Moves the connection that sends a message to another room of the same kind
+    #[derive(Debug)]
+    struct Lobby;
+    impl State for Lobby {
+        fn to_json(&self) -> JsonValue {
+            json!({})
+        }
+
+        fn on_message(&mut self, conn_id: &str, _msg: &Message, _room: &mut Room, server: &mut Arena) {
+            server.join_or_create(conn_id, "lobby", &JsonValue::Null).unwrap();
+        }
+    }
+
+    #[test]
+    fn join_or_create_from_a_room_of_the_same_kind() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.register_state("lobby", |_| Ok(Box::new(Lobby)));
+        let conn = arena.new_conn().unwrap();
+        let first = arena.join_or_create(&conn.id, "lobby", &JsonValue::Null).unwrap();
+
+        //the room handling the message is locked, it must be skipped instead of waiting for it
+        arena.get_room(&first).unwrap().lock().on_message(&conn.id, &Message::new("move", &JsonValue::Null));
+        assert_eq!(arena.room_len_by_kind("lobby"), 2);
+    }
+
+    #[test]
+    fn join_or_create_waits_for_the_rooms_locked_by_other_threads() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.register_state("game", |_| Ok(Box::new(EmptyState)));
+        let a = arena.new_conn().unwrap();
+        let b = arena.new_conn().unwrap();
+        let room_id = arena.join_or_create(&a.id, "game", &JsonValue::Null).unwrap();
+
+        let (locked_send, locked_recv) = channel::bounded(0);
+        let container = arena.get_room(&room_id).unwrap();
+        let handle = thread::spawn(move || {
+            let _guard = container.lock();
+            locked_send.send(());
+            thread::sleep(Duration::from_millis(20));
+        });
+
+        locked_recv.recv().unwrap();
+        assert_eq!(arena.join_or_create(&b.id, "game", &JsonValue::Null).unwrap(), room_id);
+        assert_eq!(arena.room_len_by_kind("game"), 1);
+        handle.join().unwrap();
+    }
+
+    /// Room of one player that counts the messages of the players and the spectators
+    #[derive(Debug, Default)]
+    struct Duel {
+        moves: usize,
+        comments: usize,
+    }
+
+    impl State for Duel {
+        fn to_json(&self) -> JsonValue {
+            json!({"moves": self.moves})
+        }
+
+        fn on_init(&mut self, room: &mut Room, _server: &mut Arena) {
+            room.set_max_connections(1);
+        }
+
+        fn on_message(&mut self, _conn_id: &str, _msg: &Message, _room: &mut Room, _server: &mut Arena) {
+            self.moves += 1;
+        }
+
+        fn on_spectator_message(&mut self, _conn_id: &str, _msg: &Message, _room: &mut Room, _server: &mut Arena) {
+            self.comments += 1;
+        }
+    }
+
+    #[test]
+    fn spectators_join_full_rooms_without_playing() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let room_id = arena.add("duel", Box::new(Duel::default())).unwrap();
+        let player = arena.new_conn().unwrap();
+        let spectator = arena.new_conn().unwrap();
+
+        arena.add_connection_to(&room_id, player.clone()).unwrap();
+        assert!(arena.add_connection_to(&room_id, spectator.clone()).is_err());
+        arena.add_spectator_to(&room_id, spectator.clone(), &JsonValue::Null).unwrap();
+        //a player can't take a seat twice as spectator
+        assert!(arena.add_spectator_to(&room_id, player.clone(), &JsonValue::Null).is_err());
+
+        let container = arena.get_room(&room_id).unwrap();
+        let mut container = container.lock();
+        assert_eq!(container.room().players_len(), 1);
+        assert_eq!(container.room().spectators_len(), 1);
+        assert_eq!(container.room().role(&spectator.id), Some(Role::Spectator));
+
+        container.on_message(&player.id, &Message::new("move", &JsonValue::Null));
+        container.on_message(&spectator.id, &Message::new("comment", &JsonValue::Null));
+        let duel = container.state().downcast_ref::<Duel>().unwrap();
+        assert_eq!((duel.moves, duel.comments), (1, 1));
+    }
+
+    #[test]
+    fn add_connections_skips_the_repeated_connections() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let room_id = arena.add("game", Box::new(EmptyState)).unwrap();
+        arena.get_room(&room_id).unwrap().lock().room.set_max_connections(1);
+        let conn = arena.new_conn().unwrap();
+
+        arena.add_connections_to(&room_id, &[conn.id.clone(), conn.id.clone()]).unwrap();
+        assert_eq!(arena.get_room(&room_id).unwrap().lock().room().connection_ids(), vec![conn.id.clone()]);
+
+        //already in the room
+        arena.add_connections_to(&room_id, &[conn.id.clone()]).unwrap();
+        assert_eq!(arena.get_room(&room_id).unwrap().lock().room().players_len(), 1);
+    }
+
+    #[test]
+    fn expire_suspended_keeps_the_resumed_connections() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.set_reconnect_timeout(Some(Duration::from_millis(20)));
+        let conn = arena.new_conn().unwrap();
+
+        arena.suspend_connection(&conn.id);
+        arena.resume_conn(&conn.token()).unwrap();
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(arena.expire_suspended(), None);
+        assert!(closed_connections(&arena).is_empty());
+    }
+
+    /// Patches of the syncs received by a connection
+    fn synced_patches(conn: &Connection) -> Vec<JsonValue> {
+        let recv = conn.listen();
+        let mut patches = vec![];
+        while let Some(evt) = recv.try_recv() {
+            if let ClientEvents::Msg(_, msg) = evt {
+                if msg.event == "sync" {
+                    patches.push(msg.data["patch"].clone());
+                }
+            }
+        }
+
+        patches
+    }
+
+    /// Records its changes and hides the secret to the spectators
+    #[derive(Debug, Default)]
+    struct Vault {
+        score: u32,
+        secret: u32,
+        changes: ChangeTracker,
+        filter_changes: bool,
+    }
+
+    impl State for Vault {
+        fn to_json(&self) -> JsonValue {
+            json!({"score": self.score, "secret": self.secret})
+        }
+
+        fn on_message(&mut self, _conn_id: &str, _msg: &Message, _room: &mut Room, _server: &mut Arena) {
+            self.score += 1;
+            self.secret += 2;
+            self.changes.replace("/score", &self.score);
+            self.changes.replace("/secret", &self.secret);
+        }
+
+        fn take_changes(&mut self) -> Option<Vec<Change>> {
+            Some(self.changes.take())
+        }
+
+        fn spectator_sync_change(&self, change: &Change, _conn_id: &str) -> Option<Change> {
+            if self.filter_changes && change.path() == "/secret" {
+                return None;
+            }
+
+            Some(change.clone())
+        }
+
+        fn to_spectator_sync(&self, _conn_id: &str) -> JsonValue {
+            json!({"score": self.score})
+        }
+    }
+
+    fn vault_room(arena: &mut Arena, filter_changes: bool) -> (RoomId, Connection, Connection) {
+        let room_id = arena.add("vault", Box::new(Vault { filter_changes: filter_changes, ..Vault::default() })).unwrap();
+        let player = arena.new_conn().unwrap();
+        let spectator = arena.new_conn().unwrap();
+        arena.add_connection_to(&room_id, player.clone()).unwrap();
+        arena.add_spectator_to(&room_id, spectator.clone(), &JsonValue::Null).unwrap();
+        synced_patches(&player);
+        synced_patches(&spectator);
+
+        (room_id, player, spectator)
+    }
+
+    #[test]
+    fn sync_changes_uses_the_view_of_the_role() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let (room_id, player, spectator) = vault_room(&mut arena, true);
+
+        arena.get_room(&room_id).unwrap().lock().on_message(&player.id, &Message::new("play", &JsonValue::Null));
+
+        assert_eq!(synced_patches(&player), vec![json!([
+            {"op": "replace", "path": "/score", "value": 1},
+            {"op": "replace", "path": "/secret", "value": 2}
+        ])]);
+        assert_eq!(synced_patches(&spectator), vec![json!([{"op": "replace", "path": "/score", "value": 1}])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "don't match its view")]
+    fn sync_changes_checks_the_custom_views() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        //to_spectator_sync hides the secret but its changes are sent
+        let (room_id, player, _) = vault_room(&mut arena, false);
+
+        arena.get_room(&room_id).unwrap().lock().on_message(&player.id, &Message::new("play", &JsonValue::Null));
+    }
+
+    /// Room with one player of Duel using the sync policy, with the events of the join already read
+    fn duel_with_policy(arena: &mut Arena, policy: SyncPolicy) -> (Arc<Mutex<RoomContainer>>, Connection) {
+        let room_id = arena.add("duel", Box::new(Duel::default())).unwrap();
+        let player = arena.new_conn().unwrap();
+        arena.add_connection_to(&room_id, player.clone()).unwrap();
+        synced_patches(&player);
+
+        let container = arena.get_room(&room_id).unwrap();
+        container.lock().room.set_sync_policy(policy);
+        (container, player)
+    }
+
+    fn play(container: &Arc<Mutex<RoomContainer>>, conn: &Connection, moves: usize) {
+        for _ in 0..moves {
+            container.lock().on_message(&conn.id, &Message::new("move", &JsonValue::Null));
+        }
+    }
+
+    #[test]
+    fn immediate_policy_syncs_every_message() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let (container, player) = duel_with_policy(&mut arena, SyncPolicy::Immediate);
+
+        play(&container, &player, 3);
+        assert_eq!(synced_patches(&player).len(), 3);
+        assert_eq!(container.lock().on_flush(), None);
+    }
+
+    #[test]
+    fn interval_policy_merges_the_messages_until_the_flush() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let (container, player) = duel_with_policy(&mut arena, SyncPolicy::Interval(Duration::from_secs(60)));
+
+        play(&container, &player, 3);
+        assert!(synced_patches(&player).is_empty());
+        let last_sync = container.lock().room.last_sync;
+        let next = container.lock().on_flush_at(last_sync + Duration::from_secs(30));
+        assert_eq!(next, Some(last_sync + Duration::from_secs(60)));
+        assert!(synced_patches(&player).is_empty());
+
+        assert_eq!(container.lock().on_flush_at(next.unwrap()), None);
+        assert_eq!(synced_patches(&player), vec![json!([{"op": "replace", "path": "/moves", "value": 3}])]);
+    }
+
+    #[test]
+    fn max_rate_policy_syncs_at_once_when_the_last_sync_is_old() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let (container, player) = duel_with_policy(&mut arena, SyncPolicy::MaxRate(1));
+        //a sync 1 minute from now keeps the messages waiting whatever the speed of the test
+        let last_sync = Instant::now() + Duration::from_secs(60);
+        container.lock().room.last_sync = last_sync;
+
+        play(&container, &player, 2);
+        assert!(synced_patches(&player).is_empty());
+
+        assert_eq!(container.lock().on_flush_at(last_sync + Duration::from_secs(1)), None);
+        assert_eq!(synced_patches(&player).len(), 1);
+
+        container.lock().room.last_sync = Instant::now() - Duration::from_secs(1);
+        play(&container, &player, 1);
+        assert_eq!(synced_patches(&player), vec![json!([{"op": "replace", "path": "/moves", "value": 3}])]);
+    }
+
+    #[test]
+    fn full_queue_is_closed_when_coalesce_frees_no_space() {
+        let (arena_send, arena_recv) = channel::unbounded();
+        let metrics = Metrics::new();
+        let conn = Connection::new();
+        conn.limit_outbound(OutboundLimit::new(4, OutboundPolicy::Coalesce), metrics.clone(), arena_send);
+
+        let sync = |seq: u64| ClientEvents::Msg("a".to_string(), Message::new("sync", &json!({"seq": seq, "patch": []})));
+        for seq in 0..4 {
+            conn.dispatch(sync(seq));
+        }
+
+        //the syncs are merged in one
+        conn.dispatch(sync(4));
+        assert_eq!(conn.pending(), 2);
+        assert!(arena_recv.try_recv().is_none());
+
+        for _ in 0..2 {
+            conn.dispatch(ClientEvents::Msg("a".to_string(), Message::new("chat", &JsonValue::Null)));
+        }
+
+        //only 1 sync is merged, the connection is closed instead of filling the queue again
+        conn.dispatch(sync(5));
+        conn.dispatch(sync(6));
+        //the 3 events left and the close
+        assert_eq!(conn.pending(), 4);
+        assert_eq!(metrics.queues().disconnected, 1);
+        match arena_recv.try_recv() {
+            Some(RoomEvents::CloseConnection(id)) => assert_eq!(id, conn.id),
+            evt => panic!("Unexpected event {:?}", evt)
+        }
+    }
+
+    /// Transport of a client that stopped reading, it only takes the first events
+    struct StalledOutbound {
+        credit: usize,
+        delivered: usize,
+        closed: Option<Option<String>>,
+    }
+
+    impl Outbound for StalledOutbound {
+        fn deliver(&mut self, _evt: &ClientEvents) {
+            self.credit -= 1;
+            self.delivered += 1;
+        }
+
+        fn close(&mut self, reason: Option<String>) {
+            self.closed = Some(reason);
+        }
+
+        fn ready(&mut self) -> bool {
+            self.credit > 0
+        }
+    }
+
+    /// Deliver every sync as soon as it's dispatched to a client that takes only 2 of them
+    fn stall(policy: OutboundPolicy, syncs: u64) -> (Connection, Metrics, channel::Receiver<RoomEvents>, StalledOutbound) {
+        let (arena_send, arena_recv) = channel::unbounded();
+        let metrics = Metrics::new();
+        let conn = Connection::new();
+        conn.limit_outbound(OutboundLimit::new(4, policy), metrics.clone(), arena_send);
+
+        let events = conn.listen();
+        let mut out = StalledOutbound { credit: 2, delivered: 0, closed: None };
+        for seq in 0..syncs {
+            conn.dispatch(ClientEvents::Msg("a".to_string(), Message::new("sync", &json!({"seq": seq, "patch": []}))));
+            if !deliver_events(&conn, &events, &mut out) {
+                break;
+            }
+        }
+
+        (conn, metrics, arena_recv, out)
+    }
+
+    #[test]
+    fn stalled_outbound_keeps_the_events_in_the_queue() {
+        let (conn, metrics, _, out) = stall(OutboundPolicy::DropSyncs, 6);
+        assert_eq!(out.delivered, 2);
+        assert_eq!(conn.pending(), 4);
+        assert_eq!(metrics.queues().max_pending, 4);
+        assert_eq!(metrics.queues().dropped_syncs, 0);
+    }
+
+    #[test]
+    fn stalled_outbound_drops_the_syncs() {
+        let (conn, metrics, _, out) = stall(OutboundPolicy::DropSyncs, 7);
+        assert_eq!(out.delivered, 2);
+        assert_eq!(conn.pending(), 1);
+        assert_eq!(metrics.queues().dropped_syncs, 4);
+        assert!(conn.needs_resync("a"));
+    }
+
+    #[test]
+    fn stalled_outbound_coalesces_the_syncs() {
+        let (conn, metrics, _, out) = stall(OutboundPolicy::Coalesce, 7);
+        assert_eq!(out.delivered, 2);
+        //the merged sync and the new one
+        assert_eq!(conn.pending(), 2);
+        assert_eq!(metrics.queues().coalesced_syncs, 3);
+        assert!(out.closed.is_none());
+    }
+
+    #[test]
+    fn stalled_outbound_is_disconnected() {
+        let (conn, metrics, arena_recv, out) = stall(OutboundPolicy::Disconnect, 7);
+        assert_eq!(out.delivered, 2);
+        //the close isn't stuck behind the events the client doesn't read
+        assert_eq!(out.closed, Some(Some("Too many pending events.".to_string())));
+        assert_eq!(conn.pending(), 0);
+        assert_eq!(metrics.queues().disconnected, 1);
+        match arena_recv.try_recv() {
+            Some(RoomEvents::CloseConnection(id)) => assert_eq!(id, conn.id),
+            evt => panic!("Unexpected event {:?}", evt)
+        }
+    }
+
+    #[test]
+    fn resume_connection_keeps_the_pending_syncs_of_other_rooms() {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        let (container, player) = duel_with_policy(&mut arena, SyncPolicy::Interval(Duration::from_secs(60)));
+        let other = arena.new_conn().unwrap();
+
+        play(&container, &player, 1);
+        container.lock().resume_connection(&other.id);
+        assert!(synced_patches(&player).is_empty());
+
+        container.lock().resume_connection(&player.id);
+        assert_eq!(synced_patches(&player), vec![json!([{"op": "replace", "path": "/moves", "value": 1}])]);
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+
+use {Arena, ArenaError, ConnId, RoomId, RoomEvents, JsonValue};
+
+/// Connection waiting for a match
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub conn_id: ConnId,
+    pub kind: String,
+    pub skill: f32,
+    pub region: Option<String>,
+    pub party: Option<String>,
+    /// Room left by the connection once it's placed in a match, like a waiting room
+    pub lobby: Option<RoomId>,
+    created: Instant,
+}
+
+impl Ticket {
+    pub fn new(conn_id: &str, kind: &str) -> Ticket {
+        Ticket {
+            conn_id: conn_id.to_string(),
+            kind: kind.to_string(),
+            skill: 0.0,
+            region: None,
+            party: None,
+            lobby: None,
+            created: Instant::now(),
+        }
+    }
+
+    pub fn waiting(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+/// How the tickets of a room kind are grouped into a match
+#[derive(Debug, Clone)]
+pub struct MatchRules {
+    /// Connections needed to start a match
+    pub players: usize,
+    /// Connections needed to start a match once the oldest ticket reaches the timeout
+    pub min_players: usize,
+    /// Max difference of skill between the players of a match
+    pub max_skill_gap: f32,
+    /// Amount added to max_skill_gap for every second waited by the oldest ticket
+    pub skill_gap_growth: f32,
+    /// Only match connections with the same region tag
+    pub same_region: bool,
+    pub timeout: Duration,
+}
+
+impl MatchRules {
+    pub fn new(players: usize) -> MatchRules {
+        MatchRules {
+            players: players,
+            min_players: players,
+            max_skill_gap: ::std::f32::MAX,
+            skill_gap_growth: 0.0,
+            same_region: false,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    fn skill_gap(&self, waiting: Duration) -> f32 {
+        let secs = waiting.as_secs() as f32 + waiting.subsec_nanos() as f32 / 1_000_000_000.0;
+        self.max_skill_gap + self.skill_gap_growth * secs
+    }
+}
+
+/// Tickets of the same party that must be placed in the same match
+#[derive(Debug)]
+struct Group {
+    tickets: Vec<Ticket>,
+    skill: f32,
+    created: Instant,
+}
+
+impl Group {
+    fn new(tickets: Vec<Ticket>) -> Group {
+        let skill = tickets.iter().map(|t| t.skill).sum::<f32>() / tickets.len() as f32;
+        let created = tickets.iter().map(|t| t.created).min().unwrap_or_else(Instant::now);
+
+        Group {
+            tickets: tickets,
+            skill: skill,
+            created: created,
+        }
+    }
+}
+
+struct InnerMatchmaker {
+    kinds: HashMap<String, MatchRules>,
+    queue: Vec<Ticket>,
+}
+
+/// Queue of connections that are grouped in matches and moved to new rooms.
+/// It's polled by `Arena::run_ticks` once it's set with `Arena::set_matchmaker`.
+#[derive(Clone)]
+pub struct Matchmaker {
+    inner: Arc<Mutex<InnerMatchmaker>>
+}
+
+impl ::std::fmt::Debug for Matchmaker {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Matchmaker {{ queued: {} }}", self.queue_len())
+    }
+}
+
+impl Matchmaker {
+    pub fn new() -> Matchmaker {
+        Matchmaker {
+            inner: Arc::new(Mutex::new(InnerMatchmaker {
+                kinds: HashMap::new(),
+                queue: vec![],
+            }))
+        }
+    }
+
+    /// Set the rules of a kind, its rooms are created with the state registered in the arena
+    pub fn register(&mut self, kind: &str, rules: MatchRules) {
+        self.inner.lock().kinds.insert(kind.to_string(), rules);
+    }
+
+    /// Add a ticket to the queue, a previous ticket of the same connection is replaced.
+    /// The tickets of a party that doesn't fit in a match are rejected.
+    pub fn enqueue(&mut self, ticket: Ticket) -> Result<(), ArenaError> {
+        let mut inner = self.inner.lock();
+        let players = match inner.kinds.get(&ticket.kind) {
+            Some(rules) => rules.players,
+            None => return Err(ArenaError::MatchRulesNotFound { kind: ticket.kind.clone() })
+        };
+
+        inner.queue.retain(|t| t.conn_id != ticket.conn_id);
+
+        if let Some(party) = &ticket.party {
+            let members = inner.queue.iter()
+                .filter(|t| t.kind == ticket.kind && t.party.as_ref() == Some(party))
+                .count();
+
+            if members + 1 > players {
+                return Err(ArenaError::PartyTooLarge { party: party.clone(), players: players });
+            }
+        }
+
+        inner.queue.push(ticket);
+        Ok(())
+    }
+
+    pub fn dequeue(&mut self, conn_id: &str) -> Option<Ticket> {
+        let mut inner = self.inner.lock();
+        let index = inner.queue.iter().position(|t| t.conn_id == conn_id)?;
+        Some(inner.queue.remove(index))
+    }
+
+    pub fn is_queued(&self, conn_id: &str) -> bool {
+        self.inner.lock().queue.iter().any(|t| t.conn_id == conn_id)
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.inner.lock().queue.len()
+    }
+
+    /// Group the queued tickets in matches, creating a room for each one, returns the ids of the new rooms
+    pub fn poll(&mut self, server: &mut Arena) -> Vec<RoomId> {
+        let mut matches = vec![];
+
+        {
+            let mut inner = self.inner.lock();
+
+            if inner.queue.is_empty() {
+                return vec![];
+            }
+
+            //tickets of connections closed meanwhile are discarded
+            inner.queue.retain(|t| server.has_connection(&t.conn_id));
+
+            //a party is matched in the region of its first ticket, even if its members have other regions
+            let mut party_regions: HashMap<(String, String), Option<String>> = HashMap::new();
+            for t in &inner.queue {
+                if let Some(party) = &t.party {
+                    party_regions.entry((t.kind.clone(), party.clone())).or_insert(t.region.clone());
+                }
+            }
+
+            let queue: Vec<Ticket> = inner.queue.drain(..).collect();
+            let mut buckets: HashMap<(String, Option<String>), Vec<Ticket>> = HashMap::new();
+            for t in queue {
+                let same_region = inner.kinds.get(&t.kind).map_or(false, |rules| rules.same_region);
+                let region = match &t.party {
+                    _ if !same_region => None,
+                    Some(party) => party_regions[&(t.kind.clone(), party.clone())].clone(),
+                    None => t.region.clone()
+                };
+                buckets.entry((t.kind.clone(), region)).or_insert(vec![]).push(t);
+            }
+
+            let mut waiting = vec![];
+            for ((kind, _), tickets) in buckets {
+                match inner.kinds.get(&kind) {
+                    Some(rules) => {
+                        let (found, rest) = find_matches(rules, tickets);
+                        matches.extend(found.into_iter().map(|m| (kind.clone(), m)));
+                        waiting.extend(rest);
+                    },
+                    None => waiting.extend(tickets)
+                }
+            }
+
+            inner.queue = waiting;
+        }
+
+        //the lock is released to let the new rooms use the matchmaker from their callbacks
+        let mut rooms = vec![];
+        for (kind, tickets) in matches {
+            match create_match(server, &kind, &tickets) {
+                Ok(id) => rooms.push(id),
+                Err(e) => {
+                    println!("Error creating a match of {}: {}", kind, e);
+                    self.inner.lock().queue.extend(tickets);
+                }
+            }
+        }
+
+        rooms
+    }
+}
+
+/// Split the tickets of one bucket into matches and the tickets that keep waiting
+fn find_matches(rules: &MatchRules, tickets: Vec<Ticket>) -> (Vec<Vec<Ticket>>, Vec<Ticket>) {
+    let mut parties: HashMap<String, Vec<Ticket>> = HashMap::new();
+    let mut groups = vec![];
+    for t in tickets {
+        match t.party.clone() {
+            Some(p) => parties.entry(p).or_insert(vec![]).push(t),
+            None => groups.push(Group::new(vec![t]))
+        }
+    }
+
+    groups.extend(parties.into_iter().map(|(_, t)| Group::new(t)));
+    groups.sort_by(|a, b| a.skill.partial_cmp(&b.skill).unwrap_or(::std::cmp::Ordering::Equal));
+
+    let mut matches = vec![];
+    let mut i = 0;
+    while i < groups.len() {
+        match take_match(rules, &groups[i..]) {
+            Some(indices) => {
+                //indices are relative to i and sorted, remove from the end to keep them valid
+                let mut tickets = vec![];
+                for index in indices.into_iter().rev() {
+                    tickets.extend(groups.remove(i + index).tickets);
+                }
+                matches.push(tickets);
+            },
+            None => i += 1
+        }
+    }
+
+    let rest = groups.into_iter()
+        .flat_map(|g| g.tickets)
+        .collect();
+
+    (matches, rest)
+}
+
+/// Try to build a match starting with the first group, the groups are sorted by skill
+fn take_match(rules: &MatchRules, groups: &[Group]) -> Option<Vec<usize>> {
+    let first = &groups[0];
+    let mut indices = vec![];
+    let mut players = 0;
+    let mut oldest = first.created;
+
+    for (index, g) in groups.iter().enumerate() {
+        if players + g.tickets.len() > rules.players {
+            continue;
+        }
+
+        let created = oldest.min(g.created);
+        if g.skill - first.skill > rules.skill_gap(created.elapsed()) {
+            break;
+        }
+
+        oldest = created;
+        players += g.tickets.len();
+        indices.push(index);
+
+        if players == rules.players {
+            return Some(indices);
+        }
+    }
+
+    let timeout = oldest.elapsed() >= rules.timeout;
+    if timeout && players >= rules.min_players && players > 0 {
+        Some(indices)
+    } else {
+        None
+    }
+}
+
+fn create_match(server: &mut Arena, kind: &str, tickets: &[Ticket]) -> Result<RoomId, ArenaError> {
+    let state = server.build_state(kind, &JsonValue::Null)?;
+    let id = server.add(kind, state)?;
+    let conn_ids: Vec<ConnId> = tickets.iter().map(|t| t.conn_id.clone()).collect();
+
+    if let Err(e) = server.add_connections_to(&id, &conn_ids) {
+        server.remove(&id)?;
+        return Err(e);
+    }
+
+    //the lobbies are left by the run loop, the lobby could be the room polling the matchmaker
+    for t in tickets {
+        if let Some(lobby) = &t.lobby {
+            server.send(RoomEvents::CloseRoom(lobby.clone(), t.conn_id.clone()));
+        }
+    }
+
+    println!("Match {}:{} created with {:?}", kind, id, conn_ids);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EmptyState;
+
+    fn ticket(conn_id: &str, skill: f32, party: Option<&str>, region: Option<&str>) -> Ticket {
+        Ticket {
+            skill: skill,
+            party: party.map(|p| p.to_string()),
+            region: region.map(|r| r.to_string()),
+            ..Ticket::new(conn_id, "game")
+        }
+    }
+
+    fn ids(tickets: &[Ticket]) -> Vec<String> {
+        let mut ids: Vec<String> = tickets.iter().map(|t| t.conn_id.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    fn matchmaker(rules: MatchRules) -> Matchmaker {
+        let mut matchmaker = Matchmaker::new();
+        matchmaker.register("game", rules);
+        matchmaker
+    }
+
+    #[test]
+    fn find_matches_groups_the_closest_skills() {
+        let rules = MatchRules { max_skill_gap: 10.0, ..MatchRules::new(2) };
+        let tickets = vec![
+            ticket("a", 0.0, None, None),
+            ticket("b", 100.0, None, None),
+            ticket("c", 5.0, None, None),
+        ];
+
+        let (matches, rest) = find_matches(&rules, tickets);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(ids(&matches[0]), vec!["a", "c"]);
+        assert_eq!(ids(&rest), vec!["b"]);
+    }
+
+    #[test]
+    fn find_matches_keeps_the_parties_together() {
+        let rules = MatchRules::new(2);
+        let tickets = vec![
+            ticket("a", 0.0, None, None),
+            ticket("b", 0.0, Some("p"), None),
+            ticket("c", 0.0, Some("p"), None),
+        ];
+
+        let (matches, rest) = find_matches(&rules, tickets);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(ids(&matches[0]), vec!["b", "c"]);
+        assert_eq!(ids(&rest), vec!["a"]);
+    }
+
+    #[test]
+    fn find_matches_uses_min_players_after_the_timeout() {
+        let rules = MatchRules { min_players: 1, timeout: Duration::from_secs(0), ..MatchRules::new(2) };
+        let (matches, rest) = find_matches(&rules, vec![ticket("a", 0.0, None, None)]);
+        assert_eq!(matches.len(), 1);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn enqueue_rejects_a_party_larger_than_a_match() {
+        let mut matchmaker = matchmaker(MatchRules::new(2));
+        matchmaker.enqueue(ticket("a", 0.0, Some("p"), None)).unwrap();
+        matchmaker.enqueue(ticket("b", 0.0, Some("p"), None)).unwrap();
+        //a new ticket of a queued connection replaces it
+        matchmaker.enqueue(ticket("b", 0.0, Some("p"), None)).unwrap();
+
+        match matchmaker.enqueue(ticket("c", 0.0, Some("p"), None)) {
+            Err(ArenaError::PartyTooLarge { party, players }) => {
+                assert_eq!(party, "p");
+                assert_eq!(players, 2);
+            },
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert_eq!(matchmaker.queue_len(), 2);
+    }
+
+    fn arena() -> Arena {
+        let mut arena = Arena::with_main_room("main", Box::new(EmptyState));
+        arena.register_state("game", |_| Ok(Box::new(EmptyState)));
+        arena
+    }
+
+    #[test]
+    fn poll_matches_a_party_in_the_region_of_its_first_ticket() {
+        let mut arena = arena();
+        let a = arena.new_conn().unwrap();
+        let b = arena.new_conn().unwrap();
+
+        let mut matchmaker = matchmaker(MatchRules { same_region: true, ..MatchRules::new(2) });
+        matchmaker.enqueue(ticket(&a.id, 0.0, Some("p"), Some("eu"))).unwrap();
+        matchmaker.enqueue(ticket(&b.id, 0.0, Some("p"), Some("us"))).unwrap();
+
+        let rooms = matchmaker.poll(&mut arena);
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(matchmaker.queue_len(), 0);
+
+        let room = arena.get_room(&rooms[0]).unwrap();
+        let mut conn_ids = room.lock().room().connection_ids();
+        conn_ids.sort();
+        let mut expected = vec![a.id.clone(), b.id.clone()];
+        expected.sort();
+        assert_eq!(conn_ids, expected);
+    }
+
+    #[test]
+    fn poll_removes_the_matched_connections_from_their_lobby() {
+        let mut arena = arena();
+        let lobby = arena.main_room().unwrap();
+        let a = arena.new_conn().unwrap();
+
+        let mut matchmaker = matchmaker(MatchRules::new(1));
+        matchmaker.enqueue(Ticket { lobby: Some(lobby.clone()), ..Ticket::new(&a.id, "game") }).unwrap();
+        assert_eq!(matchmaker.poll(&mut arena).len(), 1);
+
+        let mut left = vec![];
+        while let Some(evt) = arena.in_recv.try_recv() {
+            if let RoomEvents::CloseRoom(room_id, conn_id) = evt {
+                left.push((room_id, conn_id));
+            }
+        }
+        assert_eq!(left, vec![(lobby, a.id.clone())]);
+    }
+}
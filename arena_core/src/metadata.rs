@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use {ArenaError, JsonValue};
+
+/// Attributes of a connection like the display name or custom fields, shared by all its clones.
+/// The values are stored as json so they can be read with any type that deserializes from them.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    values: Arc<RwLock<HashMap<String, JsonValue>>>,
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        Metadata::default()
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: T) -> Result<(), ArenaError> {
+        let json = serde_json::to_value(value)
+            .map_err(|e| ArenaError::InvalidMetadata { key: key.to_string(), reason: e.to_string() })?;
+
+        self.values.write().insert(key.to_string(), json);
+        Ok(())
+    }
+
+    /// Value of a key, None if it doesn't exist or it can't be read as T
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let values = self.values.read();
+        let json = values.get(key)?;
+        serde_json::from_value(json.clone()).ok()
+    }
+
+    pub fn get_json(&self, key: &str) -> Option<JsonValue> {
+        self.values.read().get(key).cloned()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.values.read().contains_key(key)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<JsonValue> {
+        self.values.write().remove(key)
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.values.read().keys().cloned().collect()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        json!(*self.values.read())
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use parking_lot::RwLock;
+
+/// Bytes of the messages compressed by the adapters before sending them
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompressionStats {
+    pub messages: u64,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    pub fn saved_bytes(&self) -> i64 {
+        self.original_bytes as i64 - self.compressed_bytes as i64
+    }
+}
+
+/// Outbound queues of the connections with a limit, see `Arena::set_outbound_limit`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueStats {
+    /// Most events waiting at the same time in the queue of a connection
+    pub max_pending: usize,
+    pub dropped_syncs: u64,
+    pub coalesced_syncs: u64,
+    pub disconnected: u64,
+}
+
+/// Counters shared by all the clones of an arena, used by the adapters and read by the monitor
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    compression: Arc<RwLock<HashMap<String, CompressionStats>>>,
+    queues: Arc<RwLock<QueueStats>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            compression: Arc::new(RwLock::new(HashMap::new())),
+            queues: Arc::new(RwLock::new(QueueStats::default())),
+        }
+    }
+
+    pub fn record_compression(&self, kind: &str, original: usize, compressed: usize) {
+        let mut compression = self.compression.write();
+        let stats = compression.entry(kind.to_string()).or_insert_with(CompressionStats::default);
+        stats.messages += 1;
+        stats.original_bytes += original as u64;
+        stats.compressed_bytes += compressed as u64;
+    }
+
+    /// Compression stats by room kind
+    pub fn compression(&self) -> HashMap<String, CompressionStats> {
+        self.compression.read().clone()
+    }
+
+    pub fn record_pending(&self, pending: usize) {
+        //most of the events don't change the max, avoid the write lock for them
+        if pending > self.queues.read().max_pending {
+            let mut queues = self.queues.write();
+            queues.max_pending = queues.max_pending.max(pending);
+        }
+    }
+
+    pub fn record_dropped_syncs(&self, count: usize) {
+        self.queues.write().dropped_syncs += count as u64;
+    }
+
+    pub fn record_coalesced_syncs(&self, count: usize) {
+        self.queues.write().coalesced_syncs += count as u64;
+    }
+
+    pub fn record_disconnected(&self) {
+        self.queues.write().disconnected += 1;
+    }
+
+    pub fn queues(&self) -> QueueStats {
+        self.queues.read().clone()
+    }
+}
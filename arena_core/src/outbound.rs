@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use {ClientEvents, RoomId};
+
+/// What to do with the events of a connection when its outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum OutboundPolicy {
+    /// Drop the queued syncs, the rooms send a snapshot to the connection instead
+    DropSyncs,
+    /// Merge the queued syncs of every room in a single sync with all their patches
+    Coalesce,
+    /// Close the connection, the client is too slow to keep up with the rooms
+    Disconnect,
+}
+
+/// Size of the outbound queue of the connections and the policy used when it's full.
+///
+/// The queue is checked before dispatching an event, the connection is closed like with `Disconnect`
+/// when dropping or merging its syncs doesn't free at least half of the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OutboundLimit {
+    pub capacity: usize,
+    pub policy: OutboundPolicy,
+}
+
+impl OutboundLimit {
+    pub fn new(capacity: usize, policy: OutboundPolicy) -> OutboundLimit {
+        OutboundLimit {
+            capacity: capacity,
+            policy: policy,
+        }
+    }
+}
+
+fn is_sync(evt: &ClientEvents) -> bool {
+    match evt {
+        ClientEvents::Msg(_, msg) => msg.event == "sync",
+        _ => false
+    }
+}
+
+/// Room of the events that must be received in order with its syncs
+fn room_of(evt: &ClientEvents) -> Option<&RoomId> {
+    match evt {
+        ClientEvents::Msg(room, _) | ClientEvents::JoinRoom(room, _) | ClientEvents::CloseRoom(room, _) => Some(room),
+        _ => None
+    }
+}
+
+/// Remove the syncs from the events, returning the rooms that must send a snapshot
+pub fn drop_syncs(events: Vec<ClientEvents>) -> (Vec<ClientEvents>, Vec<RoomId>) {
+    let mut rooms: Vec<RoomId> = vec![];
+    let mut kept = vec![];
+
+    for evt in events {
+        if !is_sync(&evt) {
+            kept.push(evt);
+            continue;
+        }
+
+        if let Some(room) = room_of(&evt) {
+            if !rooms.contains(room) {
+                rooms.push(room.clone());
+            }
+        }
+    }
+
+    (kept, rooms)
+}
+
+/// Merge the consecutive syncs of every room, returning the number of syncs merged.
+///
+/// The merged sync keeps the `from_version` of the first one and takes the `seq` and `to_version`
+/// of the last one, the `from_seq` field has the seq of the first one for the clients that check them.
+pub fn coalesce(events: Vec<ClientEvents>) -> (Vec<ClientEvents>, usize) {
+    let mut merged = 0;
+    let mut kept: Vec<ClientEvents> = vec![];
+    //index of the sync that receives the next syncs of a room
+    let mut open: HashMap<RoomId, usize> = HashMap::new();
+
+    for evt in events {
+        if !is_sync(&evt) {
+            if let Some(room) = room_of(&evt) {
+                open.remove(room);
+            }
+
+            kept.push(evt);
+            continue;
+        }
+
+        let (room, msg) = match evt {
+            ClientEvents::Msg(room, msg) => (room, msg),
+            _ => continue
+        };
+
+        let target = match open.get(&room) {
+            Some(i) => *i,
+            None => {
+                open.insert(room.clone(), kept.len());
+                kept.push(ClientEvents::Msg(room, msg));
+                continue;
+            }
+        };
+
+        if let ClientEvents::Msg(_, first) = &mut kept[target] {
+            let data = &mut first.data;
+            if data.get("from_seq").is_none() {
+                data["from_seq"] = data["seq"].clone();
+            }
+
+            data["seq"] = msg.data["seq"].clone();
+            data["to_version"] = msg.data["to_version"].clone();
+
+            if let (Some(patch), Some(next)) = (data["patch"].as_array_mut(), msg.data["patch"].as_array()) {
+                patch.extend(next.iter().cloned());
+            }
+
+            merged += 1;
+        }
+    }
+
+    (kept, merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {JsonValue, Message};
+
+    fn sync(room: &str, seq: u64, from: u64, to: u64, path: &str) -> ClientEvents {
+        let data = json!({
+            "seq": seq,
+            "from_version": from,
+            "to_version": to,
+            "patch": [{"op": "replace", "path": path, "value": to}]
+        });
+
+        ClientEvents::Msg(room.to_string(), Message::new("sync", &data))
+    }
+
+    fn data(evt: &ClientEvents) -> &JsonValue {
+        match evt {
+            ClientEvents::Msg(_, msg) => &msg.data,
+            _ => panic!("Not a message {:?}", evt)
+        }
+    }
+
+    #[test]
+    fn drop_syncs_keeps_the_other_events() {
+        let events = vec![
+            sync("a", 1, 0, 1, "/x"),
+            ClientEvents::Msg("a".to_string(), Message::new("chat", &json!("hi"))),
+            sync("b", 1, 0, 1, "/y"),
+            sync("a", 2, 1, 2, "/x"),
+        ];
+
+        let (kept, rooms) = drop_syncs(events);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(data(&kept[0]), &json!("hi"));
+        assert_eq!(rooms, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn coalesce_merges_the_syncs_of_a_room() {
+        let events = vec![
+            sync("a", 1, 0, 1, "/x"),
+            sync("b", 4, 7, 8, "/y"),
+            sync("a", 2, 1, 2, "/z"),
+            sync("a", 3, 2, 3, "/x"),
+        ];
+
+        let (kept, merged) = coalesce(events);
+        assert_eq!(merged, 2);
+        assert_eq!(kept.len(), 2);
+
+        let a = data(&kept[0]);
+        assert_eq!((&a["from_seq"], &a["seq"]), (&json!(1), &json!(3)));
+        assert_eq!((&a["from_version"], &a["to_version"]), (&json!(0), &json!(3)));
+        let paths: Vec<&JsonValue> = a["patch"].as_array().unwrap().iter().map(|op| &op["path"]).collect();
+        assert_eq!(paths, vec!["/x", "/z", "/x"]);
+
+        //a single sync is left as it is
+        assert!(data(&kept[1]).get("from_seq").is_none());
+    }
+
+    #[test]
+    fn coalesce_stops_merging_at_the_other_events_of_the_room() {
+        let events = vec![
+            sync("a", 1, 0, 1, "/x"),
+            ClientEvents::Msg("a".to_string(), Message::new("chat", &json!("hi"))),
+            sync("a", 2, 1, 2, "/x"),
+            sync("a", 3, 2, 3, "/x"),
+            //other rooms don't end the merge group
+            ClientEvents::Msg("b".to_string(), Message::new("chat", &json!("hey"))),
+            sync("a", 4, 3, 4, "/x"),
+        ];
+
+        let (kept, merged) = coalesce(events);
+        assert_eq!(merged, 2);
+        assert_eq!(kept.len(), 4);
+        assert_eq!(data(&kept[0])["seq"], json!(1));
+        assert_eq!(data(&kept[1]), &json!("hi"));
+        assert_eq!((&data(&kept[2])["from_seq"], &data(&kept[2])["seq"]), (&json!(2), &json!(4)));
+        assert_eq!(data(&kept[3]), &json!("hey"));
+    }
+}
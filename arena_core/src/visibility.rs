@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::Serialize;
+use serde_json;
+
+use JsonValue;
+use changes::Change;
+
+/// Who can see a field of the state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visible {
+    Public,
+    /// Only the owner of the value, see `Visibility::owner`
+    Owner,
+    Hidden,
+}
+
+/// Rules to hide parts of the json of a state to some connections, used by the default `State::to_sync`
+/// and to filter the changes recorded by the states.
+/// The json of the whole state is filtered for every connection, a `SyncView` only builds what each one sees.
+///
+/// The paths are field names separated by dots, a `*` matches every key of an object and makes
+/// that key the owner of the values below it:
+///
+/// ```ignore
+/// Visibility::new()
+///     .owner("hands")             //{conn_id: cards}, every player only gets its own entry
+///     .owner("players.*.secret")  //{conn_id: {name, secret}}, the secret is removed from the other players
+///     .hidden("deck")
+/// ```
+#[derive(Debug, Clone)]
+pub struct Visibility {
+    rules: Vec<(Vec<String>, Visible)>,
+    private_by_default: bool,
+}
+
+impl Visibility {
+    pub fn new() -> Visibility {
+        Visibility {
+            rules: vec![],
+            private_by_default: false,
+        }
+    }
+
+    /// Hide the top level fields without a rule, instead of showing them
+    pub fn private_by_default(mut self) -> Visibility {
+        self.private_by_default = true;
+        self
+    }
+
+    pub fn public(self, path: &str) -> Visibility {
+        self.rule(path, Visible::Public)
+    }
+
+    /// Without a `*` on the path the field must be an object keyed by connection id
+    pub fn owner(self, path: &str) -> Visibility {
+        self.rule(path, Visible::Owner)
+    }
+
+    pub fn hidden(self, path: &str) -> Visibility {
+        self.rule(path, Visible::Hidden)
+    }
+
+    pub fn rule(mut self, path: &str, visible: Visible) -> Visibility {
+        let path = path.split('.').map(|s| s.to_string()).collect();
+        self.rules.push((path, visible));
+        self
+    }
+
+    /// Copy of the state with only what the connection can see
+    pub fn filter(&self, state: &JsonValue, conn_id: &str) -> JsonValue {
+        let mut view = state.clone();
+
+        if self.private_by_default {
+            if let Some(fields) = view.as_object_mut() {
+                let rules = &self.rules;
+                fields.retain(|name, _| rules.iter().any(|(path, _)| path[0] == *name || path[0] == "*"));
+            }
+        }
+
+        for (path, visible) in &self.rules {
+            apply(&mut view, path, *visible, conn_id, None);
+        }
+
+        view
+    }
+
+    /// Change as seen by the connection, None if it can't see it
+    pub fn filter_change(&self, change: &Change, conn_id: &str) -> Option<Change> {
+        let segments = change.segments();
+        if segments.is_empty() {
+            return match change.value() {
+                Some(value) => Some(Change::Replace(change.path().to_string(), self.filter(value, conn_id))),
+                None => Some(change.clone())
+            };
+        }
+
+        if self.private_by_default && !self.rules.iter().any(|(path, _)| path[0] == segments[0] || path[0] == "*") {
+            return None;
+        }
+
+        let mut change = change.clone();
+        for (path, visible) in &self.rules {
+            if *visible == Visible::Public {
+                continue;
+            }
+
+            let len = path.len().min(segments.len());
+            let matches = path[..len].iter().zip(&segments[..len]).all(|(p, s)| p == "*" || p == s);
+            if !matches {
+                continue;
+            }
+
+            //owner of the values matched by the last wildcard of the rule
+            let owner = path[..len].iter().zip(&segments[..len])
+                .filter(|(p, _)| *p == "*")
+                .map(|(_, s)| s.as_str())
+                .last();
+
+            if path.len() > segments.len() {
+                //the change replaces a value that contains the field of the rule
+                if let Some(value) = change.value_mut() {
+                    apply(value, &path[segments.len()..], *visible, conn_id, owner);
+                }
+                continue;
+            }
+
+            match (*visible, owner) {
+                (Visible::Hidden, _) => return None,
+                (Visible::Owner, Some(owner)) if owner != conn_id => return None,
+                (Visible::Owner, Some(_)) => {},
+                (Visible::Owner, None) => {
+                    if segments.len() > path.len() {
+                        //a value of an object keyed by connection id
+                        if segments[path.len()] != conn_id {
+                            return None;
+                        }
+                    } else if let Some(JsonValue::Object(entries)) = change.value_mut() {
+                        entries.retain(|key, _| key == conn_id);
+                    }
+                },
+                (Visible::Public, _) => {}
+            }
+        }
+
+        Some(change)
+    }
+}
+
+fn apply(value: &mut JsonValue, path: &[String], visible: Visible, conn_id: &str, owner: Option<&str>) {
+    if visible == Visible::Public || path.is_empty() {
+        return;
+    }
+
+    let fields = match value.as_object_mut() {
+        Some(fields) => fields,
+        None => return
+    };
+
+    let name = &path[0];
+    if path.len() > 1 {
+        if name == "*" {
+            for (key, child) in fields.iter_mut() {
+                apply(child, &path[1..], visible, conn_id, Some(key));
+            }
+        } else if let Some(child) = fields.get_mut(name) {
+            apply(child, &path[1..], visible, conn_id, owner);
+        }
+
+        return;
+    }
+
+    match (visible, owner) {
+        (Visible::Hidden, _) => remove(fields, name),
+        (Visible::Owner, Some(owner)) => {
+            if owner != conn_id {
+                remove(fields, name);
+            }
+        },
+        (Visible::Owner, None) => {
+            if name == "*" {
+                fields.retain(|key, _| key == conn_id);
+            } else if let Some(JsonValue::Object(entries)) = fields.get_mut(name) {
+                //an object keyed by connection id
+                entries.retain(|key, _| key == conn_id);
+            }
+        },
+        (Visible::Public, _) => {}
+    }
+}
+
+/// Remove a field, or all of them when the name is `*`
+fn remove(fields: &mut ::serde_json::Map<String, JsonValue>, name: &str) {
+    if name == "*" {
+        fields.clear();
+    } else {
+        fields.remove(name);
+    }
+}
+
+/// View of a value built for each connection without serializing what it can't see, implemented with
+/// `#[derive(SyncView)]` from arena_derive marking the fields of a struct:
+///
+/// ```ignore
+/// #[derive(Serialize, SyncView)]
+/// struct Game {
+///     turn: String,                   //public
+///     #[sync(owner)]
+///     hands: HashMap<String, Vec<u8>>, //keyed by connection id, every player only gets its own entry
+///     #[sync(view)]
+///     players: HashMap<String, Player>, //the key is the owner of the player
+///     #[sync(hidden)]
+///     deck: Vec<u8>,
+/// }
+///
+/// #[derive(Serialize, SyncView)]
+/// struct Player {
+///     name: String,
+///     #[sync(owner_only)]
+///     secret: u32,
+/// }
+///
+/// //the states use it from to_sync
+/// fn to_sync(&self, conn_id: &str) -> JsonValue {
+///     self.sync_view(conn_id, None)
+/// }
+/// ```
+///
+/// The fields skipped by serde are skipped and its renames are used, other serde attributes are not applied.
+/// The states that record their changes must also hide them in `State::sync_change`.
+pub trait SyncView {
+    /// Json seen by a connection, owner is the connection that owns the value if it's in a map keyed by connection id
+    fn sync_view(&self, conn_id: &str, owner: Option<&str>) -> JsonValue;
+}
+
+impl<V: SyncView> SyncView for HashMap<String, V> {
+    fn sync_view(&self, conn_id: &str, _owner: Option<&str>) -> JsonValue {
+        JsonValue::Object(self.iter().map(|(k, v)| (k.clone(), v.sync_view(conn_id, Some(k)))).collect())
+    }
+}
+
+impl<V: SyncView> SyncView for BTreeMap<String, V> {
+    fn sync_view(&self, conn_id: &str, _owner: Option<&str>) -> JsonValue {
+        JsonValue::Object(self.iter().map(|(k, v)| (k.clone(), v.sync_view(conn_id, Some(k)))).collect())
+    }
+}
+
+impl<V: SyncView> SyncView for Vec<V> {
+    fn sync_view(&self, conn_id: &str, owner: Option<&str>) -> JsonValue {
+        JsonValue::Array(self.iter().map(|v| v.sync_view(conn_id, owner)).collect())
+    }
+}
+
+impl<V: SyncView> SyncView for Option<V> {
+    fn sync_view(&self, conn_id: &str, owner: Option<&str>) -> JsonValue {
+        match self {
+            Some(v) => v.sync_view(conn_id, owner),
+            None => JsonValue::Null
+        }
+    }
+}
+
+/// Map keyed by connection id whose entries are only seen by their connection, see `#[sync(owner)]`
+pub trait OwnerView {
+    fn owner_view(&self, conn_id: &str) -> JsonValue;
+}
+
+impl<V: Serialize> OwnerView for HashMap<String, V> {
+    fn owner_view(&self, conn_id: &str) -> JsonValue {
+        owner_entry(self.get(conn_id), conn_id)
+    }
+}
+
+impl<V: Serialize> OwnerView for BTreeMap<String, V> {
+    fn owner_view(&self, conn_id: &str) -> JsonValue {
+        owner_entry(self.get(conn_id), conn_id)
+    }
+}
+
+fn owner_entry<V: Serialize>(value: Option<&V>, conn_id: &str) -> JsonValue {
+    let mut view = json!({});
+    if let Some(value) = value {
+        view[conn_id] = public_view(value);
+    }
+
+    view
+}
+
+/// Json of a field seen by every connection, used by the derive of SyncView
+pub fn public_view<T: Serialize>(value: &T) -> JsonValue {
+    serde_json::to_value(value).unwrap_or(JsonValue::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game() -> JsonValue {
+        json!({
+            "turn": "a",
+            "deck": [1, 2, 3],
+            "hands": {"a": [4], "b": [5]},
+            "players": {
+                "a": {"name": "Ann", "secret": 1},
+                "b": {"name": "Bob", "secret": 2}
+            }
+        })
+    }
+
+    #[test]
+    fn filter_keeps_the_own_entry_of_an_owner_map() {
+        let view = Visibility::new().owner("hands").filter(&game(), "a");
+        assert_eq!(view["hands"], json!({"a": [4]}));
+        assert_eq!(view["deck"], json!([1, 2, 3]));
+    }
+
+    #[test]
+    fn filter_removes_the_fields_of_other_owners() {
+        let view = Visibility::new().owner("players.*.secret").filter(&game(), "b");
+        assert_eq!(view["players"], json!({
+            "a": {"name": "Ann"},
+            "b": {"name": "Bob", "secret": 2}
+        }));
+    }
+
+    #[test]
+    fn filter_removes_the_hidden_fields() {
+        let view = Visibility::new().hidden("deck").hidden("players.*.secret").filter(&game(), "a");
+        assert!(view.get("deck").is_none());
+        assert_eq!(view["players"]["a"], json!({"name": "Ann"}));
+    }
+
+    #[test]
+    fn filter_private_by_default_only_keeps_the_fields_with_rules() {
+        let view = Visibility::new().private_by_default().public("turn").owner("hands").filter(&game(), "a");
+        assert_eq!(view, json!({"turn": "a", "hands": {"a": [4]}}));
+    }
+
+    #[test]
+    fn filter_private_by_default_with_a_wildcard_rule() {
+        //the state is keyed by connection id
+        let state = json!({"a": {"cards": [1]}, "b": {"cards": [2]}});
+        let view = Visibility::new().private_by_default().owner("*").filter(&state, "a");
+        assert_eq!(view, json!({"a": {"cards": [1]}}));
+
+        let change = Change::Replace("/a/cards".to_string(), json!([3]));
+        let visibility = Visibility::new().private_by_default().owner("*.cards");
+        assert_eq!(visibility.filter_change(&change, "a"), Some(change.clone()));
+        assert_eq!(visibility.filter_change(&change, "b"), None);
+    }
+
+    #[test]
+    fn filter_change_of_an_owner_map() {
+        let visibility = Visibility::new().owner("hands");
+        let change = Change::Add("/hands/a/1".to_string(), json!(6));
+        assert_eq!(visibility.filter_change(&change, "a"), Some(change.clone()));
+        assert_eq!(visibility.filter_change(&change, "b"), None);
+    }
+
+    #[test]
+    fn filter_change_with_a_wildcard_owner() {
+        let visibility = Visibility::new().owner("players.*.secret");
+        let secret = Change::Replace("/players/a/secret".to_string(), json!(3));
+        assert_eq!(visibility.filter_change(&secret, "a"), Some(secret.clone()));
+        assert_eq!(visibility.filter_change(&secret, "b"), None);
+
+        let name = Change::Replace("/players/a/name".to_string(), json!("Anna"));
+        assert_eq!(visibility.filter_change(&name, "b"), Some(name.clone()));
+    }
+
+    #[test]
+    fn filter_change_of_a_parent_path_filters_its_value() {
+        let visibility = Visibility::new().owner("players.*.secret").owner("hands").hidden("deck");
+
+        let player = Change::Add("/players/c".to_string(), json!({"name": "Cid", "secret": 3}));
+        assert_eq!(visibility.filter_change(&player, "a"), Some(Change::Add("/players/c".to_string(), json!({"name": "Cid"}))));
+        assert_eq!(visibility.filter_change(&player, "c"), Some(player.clone()));
+
+        let hands = Change::Replace("/hands".to_string(), json!({"a": [1], "b": [2]}));
+        assert_eq!(visibility.filter_change(&hands, "b"), Some(Change::Replace("/hands".to_string(), json!({"b": [2]}))));
+
+        let root = Change::Replace("".to_string(), game());
+        let filtered = visibility.filter_change(&root, "a").unwrap();
+        assert!(filtered.value().unwrap().get("deck").is_none());
+        assert_eq!(filtered.value().unwrap()["hands"], json!({"a": [4]}));
+
+        assert_eq!(visibility.filter_change(&Change::Remove("/deck/0".to_string()), "a"), None);
+    }
+}
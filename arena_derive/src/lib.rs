@@ -0,0 +1,133 @@
+//! `#[derive(SyncView)]` to build the view of a state for each connection, see `arena_core::SyncView`
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use] extern crate syn;
+#[macro_use] extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{Data, DeriveInput, Field, Fields, Lit, Meta, NestedMeta};
+
+/// Who can see a field, set with `#[sync(...)]`
+enum Rule {
+    Public,
+    Hidden,
+    /// Map keyed by connection id, every connection only sees its entry
+    Owner,
+    /// Only seen by the owner of the struct
+    OwnerOnly,
+    /// Value that implements SyncView itself
+    View,
+}
+
+#[proc_macro_derive(SyncView, attributes(sync))]
+pub fn derive_sync_view(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match sync_view(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into()
+    }
+}
+
+fn sync_view(input: &DeriveInput) -> Result<TokenStream2, syn::Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(&input.ident, "SyncView needs a struct with named fields"))
+        },
+        _ => return Err(syn::Error::new_spanned(&input.ident, "SyncView can only be derived for structs"))
+    };
+
+    let mut inserts = vec![];
+    for field in fields {
+        if serde_skipped(field) {
+            continue;
+        }
+
+        let ident = &field.ident;
+        let name = serde_name(field).unwrap_or_else(|| ident.as_ref().map(|i| i.to_string()).unwrap_or_default());
+        let insert = match rule(field)? {
+            Rule::Public => quote! {
+                view[#name] = ::arena_core::public_view(&self.#ident);
+            },
+            Rule::Hidden => continue,
+            Rule::Owner => quote! {
+                view[#name] = ::arena_core::OwnerView::owner_view(&self.#ident, conn_id);
+            },
+            Rule::OwnerOnly => quote! {
+                if owner == Some(conn_id) {
+                    view[#name] = ::arena_core::public_view(&self.#ident);
+                }
+            },
+            Rule::View => quote! {
+                view[#name] = ::arena_core::SyncView::sync_view(&self.#ident, conn_id, owner);
+            }
+        };
+        inserts.push(insert);
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::arena_core::SyncView for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn sync_view(&self, conn_id: &str, owner: Option<&str>) -> ::arena_core::JsonValue {
+                let mut view = ::arena_core::JsonValue::Object(Default::default());
+                #(#inserts)*
+                view
+            }
+        }
+    })
+}
+
+/// Words inside the attributes of a field with the given name, like `#[sync(hidden)]`
+fn attr_words(field: &Field, name: &str) -> Vec<Meta> {
+    field.attrs.iter()
+        .filter_map(|attr| attr.parse_meta().ok())
+        .filter_map(|meta| match meta {
+            Meta::List(list) if list.ident == name => Some(list.nested),
+            _ => None
+        })
+        .flat_map(|nested| nested.into_iter())
+        .filter_map(|nested| match nested {
+            NestedMeta::Meta(meta) => Some(meta),
+            _ => None
+        })
+        .collect()
+}
+
+fn rule(field: &Field) -> Result<Rule, syn::Error> {
+    let mut rule = Rule::Public;
+    for meta in attr_words(field, "sync") {
+        rule = match &meta {
+            Meta::Word(word) if word == "public" => Rule::Public,
+            Meta::Word(word) if word == "hidden" => Rule::Hidden,
+            Meta::Word(word) if word == "owner" => Rule::Owner,
+            Meta::Word(word) if word == "owner_only" => Rule::OwnerOnly,
+            Meta::Word(word) if word == "view" => Rule::View,
+            _ => return Err(syn::Error::new_spanned(meta, "expected one of public, hidden, owner, owner_only or view"))
+        };
+    }
+
+    Ok(rule)
+}
+
+fn serde_skipped(field: &Field) -> bool {
+    attr_words(field, "serde").iter().any(|meta| match meta {
+        Meta::Word(word) => word == "skip" || word == "skip_serializing",
+        _ => false
+    })
+}
+
+fn serde_name(field: &Field) -> Option<String> {
+    attr_words(field, "serde").iter()
+        .filter_map(|meta| match meta {
+            Meta::NameValue(nv) if nv.ident == "rename" => match &nv.lit {
+                Lit::Str(s) => Some(s.value()),
+                _ => None
+            },
+            _ => None
+        })
+        .last()
+}
@@ -0,0 +1,85 @@
+extern crate arena_core;
+#[macro_use] extern crate arena_derive;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use arena_core::{ChangeTracker, SyncView};
+
+#[derive(Debug, Serialize, SyncView)]
+struct Player {
+    name: String,
+    #[sync(owner_only)]
+    secret: u32,
+}
+
+#[derive(Debug, Serialize, SyncView)]
+struct Game {
+    turn: String,
+    #[sync(owner)]
+    hands: HashMap<String, Vec<u8>>,
+    #[sync(view)]
+    players: BTreeMap<String, Player>,
+    #[sync(hidden)]
+    deck: Vec<u8>,
+    #[serde(rename = "round")]
+    current_round: u32,
+    #[serde(skip)]
+    changes: ChangeTracker,
+}
+
+fn game() -> Game {
+    let mut hands = HashMap::new();
+    hands.insert("a".to_string(), vec![1]);
+    hands.insert("b".to_string(), vec![2]);
+
+    let mut players = BTreeMap::new();
+    players.insert("a".to_string(), Player { name: "Ann".to_string(), secret: 1 });
+    players.insert("b".to_string(), Player { name: "Bob".to_string(), secret: 2 });
+
+    Game {
+        turn: "a".to_string(),
+        hands: hands,
+        players: players,
+        deck: vec![3, 4],
+        current_round: 1,
+        changes: ChangeTracker::new(),
+    }
+}
+
+#[test]
+fn every_connection_only_sees_its_own_data() {
+    let game = game();
+
+    assert_eq!(game.sync_view("a", None), json!({
+        "turn": "a",
+        "hands": {"a": [1]},
+        "players": {
+            "a": {"name": "Ann", "secret": 1},
+            "b": {"name": "Bob"}
+        },
+        "round": 1
+    }));
+
+    assert_eq!(game.sync_view("b", None)["hands"], json!({"b": [2]}));
+    assert_eq!(game.sync_view("b", None)["players"]["b"]["secret"], json!(2));
+}
+
+#[test]
+fn connections_without_entries_see_the_public_fields() {
+    let view = game().sync_view("spectator", None);
+    assert_eq!(view["hands"], json!({}));
+    assert_eq!(view["players"], json!({"a": {"name": "Ann"}, "b": {"name": "Bob"}}));
+    assert!(view.get("deck").is_none());
+    assert!(view.get("changes").is_none());
+}
+
+#[test]
+fn the_public_fields_match_to_json() {
+    let game = game();
+    let json = json!(game);
+    let view = game.sync_view("a", None);
+
+    assert_eq!(view["turn"], json["turn"]);
+    assert_eq!(view["round"], json["round"]);
+}
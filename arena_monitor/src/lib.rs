@@ -1,9 +1,176 @@
 extern crate actix;
 extern crate actix_web;
+extern crate arena_core;
+#[macro_use] extern crate serde_json;
 
-use actix::*;
-use actix_web::*;
+use actix_web::{server, App, HttpRequest, HttpResponse};
+use arena_core::{Arena, RoomContainer, JsonValue};
+use std::collections::HashMap;
 
-pub fn run_monitor() {
+const INDEX_HTML: &str = include_str!("../static/index.html");
+/// Connections listed on the queue metrics
+const MAX_SLOWEST: usize = 10;
 
-}
\ No newline at end of file
+fn room_info(container: &RoomContainer) -> JsonValue {
+    let room = container.room();
+    json!({
+        "id": room.id(),
+        "kind": room.kind(),
+        "connections": room.connections_len(),
+        "spectators": room.spectators_len(),
+        "max_connections": room.get_max_connections(),
+        "tick_rate": room.get_tick_rate(),
+        "idle": container.is_idle()
+    })
+}
+
+fn index(_req: &HttpRequest<Arena>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(INDEX_HTML)
+}
+
+fn main_room(req: &HttpRequest<Arena>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "main_room": req.state().main_room()
+    }))
+}
+
+fn rooms(req: &HttpRequest<Arena>) -> HttpResponse {
+    let arena = req.state();
+
+    let mut kinds = HashMap::new();
+    for kind in arena.kinds() {
+        let rooms: Vec<JsonValue> = arena.get_rooms_by_kind(&kind).iter()
+            .map(|c| room_info(&c.lock()))
+            .collect();
+
+        kinds.insert(kind, rooms);
+    }
+
+    HttpResponse::Ok().json(json!({
+        "total": arena.room_len(),
+        "kinds": kinds
+    }))
+}
+
+fn room(req: &HttpRequest<Arena>) -> HttpResponse {
+    let id = req.match_info().get("id").unwrap_or("");
+
+    match req.state().get_room(id) {
+        Some(c) => {
+            let container = c.lock();
+            let mut info = room_info(&container);
+            info["connection_ids"] = json!(container.room().connection_ids());
+            info["state"] = container.state().to_json();
+
+            HttpResponse::Ok().json(info)
+        },
+        None => HttpResponse::NotFound().json(json!({
+            "error": format!("Room {} doesn't exists.", id)
+        }))
+    }
+}
+
+fn connections(req: &HttpRequest<Arena>) -> HttpResponse {
+    let ids = req.state().connection_ids();
+
+    HttpResponse::Ok().json(json!({
+        "total": ids.len(),
+        "connections": ids
+    }))
+}
+
+fn metrics(req: &HttpRequest<Arena>) -> HttpResponse {
+    let compression: HashMap<String, JsonValue> = req.state().metrics().compression().into_iter()
+        .map(|(kind, stats)| {
+            let mut info = json!(stats);
+            info["saved_bytes"] = json!(stats.saved_bytes());
+            (kind, info)
+        })
+        .collect();
+
+    HttpResponse::Ok().json(json!({
+        "compression": compression,
+        "queues": queues(req.state())
+    }))
+}
+
+/// Stats of the outbound queues with the connections that have more events waiting
+fn queues(arena: &Arena) -> JsonValue {
+    let mut pending: Vec<(String, usize)> = arena.connection_ids().iter()
+        .filter_map(|id| arena.get_connection(id))
+        .map(|conn| (conn.id.clone(), conn.pending()))
+        .collect();
+    pending.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let mut info = json!(arena.metrics().queues());
+    info["limit"] = json!(arena.get_outbound_limit());
+    info["pending"] = json!(pending.iter().map(|(_, p)| p).sum::<usize>());
+    info["slowest"] = pending.iter()
+        .filter(|(_, p)| *p > 0)
+        .take(MAX_SLOWEST)
+        .map(|(id, p)| json!({"id": id, "pending": p}))
+        .collect();
+    info
+}
+
+fn connection(req: &HttpRequest<Arena>) -> HttpResponse {
+    let id = req.match_info().get("id").unwrap_or("");
+
+    match req.state().get_connection(id) {
+        Some(conn) => HttpResponse::Ok().json(json!({
+            "id": conn.id,
+            "identity": conn.identity(),
+            "metadata": conn.metadata().to_json()
+        })),
+        None => HttpResponse::NotFound().json(json!({
+            "error": format!("Connection {} doesn't exists.", id)
+        }))
+    }
+}
+
+pub struct MonitorSettings {
+    /// Serve the identity and metadata of the connections on `/api/connections/{id}`, they could be private
+    pub expose_metadata: bool,
+}
+
+impl Default for MonitorSettings {
+    fn default() -> MonitorSettings {
+        MonitorSettings {
+            expose_metadata: false,
+        }
+    }
+}
+
+/// Start a read-only http server to inspect the arena, it blocks the current thread
+pub fn run_monitor(addr: &str, arena: Arena) {
+    run_monitor_with_settings(addr, arena, MonitorSettings::default());
+}
+
+pub fn run_monitor_with_settings(addr: &str, arena: Arena, settings: MonitorSettings) {
+    let expose_metadata = settings.expose_metadata;
+    let srv = server::new(move || {
+        let app = App::with_state(arena.clone())
+            .resource("/", |r| r.get().f(index))
+            .resource("/api/main_room", |r| r.get().f(main_room))
+            .resource("/api/rooms", |r| r.get().f(rooms))
+            .resource("/api/rooms/{id}", |r| r.get().f(room))
+            .resource("/api/connections", |r| r.get().f(connections))
+            .resource("/api/metrics", |r| r.get().f(metrics));
+
+        if expose_metadata {
+            app.resource("/api/connections/{id}", |r| r.get().f(connection))
+        } else {
+            app
+        }
+    });
+
+    match srv.bind(addr) {
+        Ok(s) => {
+            println!("Monitor listening on {}", addr);
+            s.run();
+        },
+        Err(e) => println!("Error initiating the monitor {}", e)
+    }
+}
@@ -0,0 +1,125 @@
+//! Opens a lot of idle websockets against an arena to check the memory and threads used by the server.
+//!
+//! cargo run --release -p arena_net --example load_test -- server 127.0.0.1:8090 20000
+//! cargo run --release -p arena_net --example load_test -- client 127.0.0.1:8090 10000
+extern crate arena_core;
+extern crate arena_net;
+extern crate ws;
+extern crate url;
+
+use arena_core::{Arena, State, Room, JsonValue, Message};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct LoadRoom;
+
+impl State for LoadRoom {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::Null
+    }
+
+    fn on_connect(&mut self, _conn_id: &str, _options: &JsonValue, _room: &mut Room, _server: &mut Arena) {}
+    fn on_disconnect(&mut self, _conn_id: &str, _room: &mut Room, _server: &mut Arena) {}
+    fn on_message(&mut self, _conn_id: &str, _msg: &Message, _room: &mut Room, _server: &mut Arena) {}
+}
+
+/// VmRSS and Threads of this process
+fn process_status() -> String {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status.lines()
+        .filter(|l| l.starts_with("VmRSS") || l.starts_with("Threads"))
+        .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn report(label: &'static str, count: Arc<AtomicUsize>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(2));
+        println!("[{}] connections: {} {}", label, count.load(Ordering::SeqCst), process_status());
+    });
+}
+
+fn server(addr: &str, max_connections: usize) {
+    let arena = Arena::with_main_room("main_room", Box::new(LoadRoom));
+
+    let stats_arena = arena.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(2));
+        println!("[server] connections: {} {}", stats_arena.connection_ids().len(), process_status());
+    });
+
+    let settings = arena_net::Settings {
+        max_connections: max_connections,
+        ..arena_net::Settings::default()
+    };
+    arena_net::run_with_settings(addr, settings, move || arena.clone());
+}
+
+struct LoadClient {
+    opened: Arc<AtomicUsize>,
+}
+
+impl ws::Handler for LoadClient {
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        //the first message of every connection is the init event
+        if let ws::Message::Text(text) = msg {
+            if text.contains("\"init\"") {
+                self.opened.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn client(addr: &str, total: usize) {
+    let opened = Arc::new(AtomicUsize::new(0));
+    report("client", opened.clone());
+
+    let factory_opened = opened.clone();
+    let mut socket = ws::Builder::new()
+        .with_settings(ws::Settings {
+            max_connections: total,
+            ..ws::Settings::default()
+        })
+        .build(move |_| LoadClient { opened: factory_opened.clone() })
+        .expect("Error building the websocket client");
+
+    let url = url::Url::parse(&format!("ws://{}/", addr)).expect("Invalid address");
+    for _ in 0..total {
+        socket.connect(url.clone()).expect("Error queueing a connection");
+    }
+
+    let start = Instant::now();
+    let broadcaster = socket.broadcaster();
+    thread::spawn(move || {
+        while opened.load(Ordering::SeqCst) < total {
+            thread::sleep(Duration::from_millis(100));
+        }
+        println!("[client] {} connections opened in {:?}", total, start.elapsed());
+        thread::sleep(Duration::from_secs(10));
+        let _ = broadcaster.shutdown();
+    });
+
+    if let Err(e) = socket.run() {
+        println!("Error running the client {}", e);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mode = args.get(1).map(|s| s.as_str()).unwrap_or("server");
+    let addr = args.get(2).map(|s| s.as_str()).unwrap_or("127.0.0.1:8090");
+    let count = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(10_000);
+
+    match mode {
+        "server" => server(addr, count),
+        "client" => client(addr, count),
+        _ => println!("Usage: load_test [server|client] [addr] [connections]"),
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use ws_rs;
+use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
+use arena_core::Identity;
+
+/// Credentials sent by a client on the handshake
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// Headers with the names in lowercase
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    pub query: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Extract the credentials of a websocket handshake request
+    pub fn from_request(req: &ws_rs::Request) -> Credentials {
+        let headers: HashMap<String, String> = req.headers().iter()
+            .filter_map(|(k, v)| String::from_utf8(v.clone()).ok().map(|v| (k.to_lowercase(), v)))
+            .collect();
+
+        let cookies = headers.get("cookie")
+            .map(|c| parse_cookies(c))
+            .unwrap_or_default();
+
+        let query = req.resource().splitn(2, '?').nth(1)
+            .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+
+        Credentials {
+            headers: headers,
+            cookies: cookies,
+            query: query,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|v| v.as_str())
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(|v| v.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|v| v.as_str())
+    }
+
+    /// Token of an `Authorization: Bearer <token>` header
+    pub fn bearer(&self) -> Option<&str> {
+        let auth = self.header("authorization")?;
+        if auth.len() > 7 && auth[..7].eq_ignore_ascii_case("bearer ") {
+            Some(auth[7..].trim())
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse the `key=value` pairs of a cookie header, the values are percent-decoded
+fn parse_cookies(s: &str) -> HashMap<String, String> {
+    s.split(';')
+        .filter_map(|pair| {
+            let mut kv = pair.trim().splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if !k.is_empty() => {
+                    Some((k.to_string(), percent_decode(v.as_bytes()).decode_utf8_lossy().into_owned()))
+                },
+                _ => None
+            }
+        })
+        .collect()
+}
+
+/// Check the credentials of the clients before opening their connections.
+/// The identity returned is attached to the connection, None accepts the client as anonymous and
+/// an error rejects the handshake with the reason.
+/// Only the `WsAdapter` uses it, the connections of the tcp and udp adapters are anonymous.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<Identity>, String>;
+}
+
+impl<F> Authenticator for F
+    where F: Fn(&Credentials) -> Result<Option<Identity>, String> + Send + Sync
+{
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<Identity>, String> {
+        self(credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cookies_decodes_the_values() {
+        let cookies = parse_cookies("session=a%2Bb%3D; theme=dark;empty");
+        assert_eq!(cookies.get("session").map(|v| v.as_str()), Some("a+b="));
+        assert_eq!(cookies.get("theme").map(|v| v.as_str()), Some("dark"));
+        assert_eq!(cookies.len(), 2);
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use serde_json;
+use rmp_serde;
+use serde_cbor;
+use arena_core::JsonValue;
+
+/// Serialization of the messages sent over the wire, the messages are always `{room, event, data}` envelopes
+pub trait Codec: Send + Sync {
+    /// Name used to negotiate the codec on the handshake
+    fn name(&self) -> &'static str;
+
+    /// Binary codecs are sent as binary websocket frames
+    fn is_binary(&self) -> bool;
+
+    fn encode(&self, msg: &JsonValue) -> Result<Vec<u8>, String>;
+
+    fn decode(&self, buf: &[u8]) -> Result<JsonValue, String>;
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    fn encode(&self, msg: &JsonValue) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(msg).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<JsonValue, String> {
+        serde_json::from_slice(buf).map_err(|e| e.to_string())
+    }
+}
+
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, msg: &JsonValue) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec(msg).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<JsonValue, String> {
+        rmp_serde::from_slice(buf).map_err(|e| e.to_string())
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, msg: &JsonValue) -> Result<Vec<u8>, String> {
+        serde_cbor::to_vec(msg).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<JsonValue, String> {
+        serde_cbor::from_slice(buf).map_err(|e| e.to_string())
+    }
+}
+
+/// Find one of the built-in codecs by its name
+pub fn codec_by_name(name: &str) -> Option<Arc<Codec>> {
+    match name {
+        "json" => Some(Arc::new(JsonCodec)),
+        "msgpack" => Some(Arc::new(MsgPackCodec)),
+        "cbor" => Some(Arc::new(CborCodec)),
+        _ => None
+    }
+}
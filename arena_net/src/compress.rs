@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use base64;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use serde_json;
+use arena_core::{Arena, ClientEvents, JsonValue};
+
+/// Events whose data is the state of a room or a patch of it
+const SYNC_EVENTS: [&str; 2] = ["sync", "snapshot"];
+
+/// Compress the data of the sync messages bigger than a threshold, the data is replaced by
+/// `{compressed: "deflate", payload}` being the payload the zlib stream of the json data encoded in base64.
+/// The bytes saved are recorded in the metrics of the arena by room kind.
+///
+/// Every connection uses its own clone, the kinds of its rooms are cached by it until it leaves them.
+#[derive(Clone)]
+pub struct SyncCompression {
+    threshold: usize,
+    arena: Arena,
+    kinds: RefCell<HashMap<String, String>>,
+}
+
+impl SyncCompression {
+    pub fn new(threshold: usize, arena: Arena) -> SyncCompression {
+        SyncCompression {
+            threshold: threshold,
+            arena: arena,
+            kinds: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Kind of a room, it's only looked up in the arena the first time
+    fn kind_of(&self, room_id: &str) -> String {
+        if let Some(kind) = self.kinds.borrow().get(room_id) {
+            return kind.clone();
+        }
+
+        match self.arena.get_room_kind(room_id) {
+            Some(kind) => {
+                self.kinds.borrow_mut().insert(room_id.to_string(), kind.clone());
+                kind
+            },
+            None => "".to_string()
+        }
+    }
+
+    pub fn apply(&self, evt: &ClientEvents, mut msg: JsonValue) -> JsonValue {
+        let room_id = match evt {
+            ClientEvents::Msg(room_id, m) if SYNC_EVENTS.contains(&m.event.as_str()) => room_id,
+            ClientEvents::CloseRoom(room_id, _) => {
+                self.kinds.borrow_mut().remove(room_id);
+                return msg;
+            },
+            _ => return msg
+        };
+
+        let text = match serde_json::to_vec(&msg["data"]) {
+            Ok(text) => text,
+            Err(_) => return msg
+        };
+
+        if text.len() <= self.threshold {
+            return msg;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::with_capacity(text.len() / 2), Compression::default());
+        let r_compressed = encoder.write_all(&text).and_then(|_| encoder.finish());
+        let payload = match r_compressed {
+            Ok(compressed) => base64::encode(&compressed),
+            Err(e) => {
+                println!("Error compressing a message of {}: {}", room_id, e);
+                return msg;
+            }
+        };
+
+        //small or random data could grow
+        if payload.len() >= text.len() {
+            return msg;
+        }
+
+        let kind = self.kind_of(room_id);
+        self.arena.metrics().record_compression(&kind, text.len(), payload.len());
+
+        msg["data"] = json!({
+            "compressed": "deflate",
+            "payload": payload
+        });
+
+        msg
+    }
+}
+
+/// Message of an event, compressed if the adapter has a compression
+pub fn compress_message(evt: &ClientEvents, msg: JsonValue, compression: &Option<SyncCompression>) -> JsonValue {
+    match compression {
+        Some(c) => c.apply(evt, msg),
+        None => msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arena_core::{EmptyState, Message};
+
+    #[test]
+    fn apply_compresses_big_syncs_and_records_them_by_kind() {
+        let mut arena = Arena::new();
+        let room_id = arena.add("game", Box::new(EmptyState)).unwrap();
+        let compression = SyncCompression::new(100, arena.clone());
+
+        let data = json!({"board": vec![0; 1000]});
+        let evt = ClientEvents::Msg(room_id.clone(), Message::new("sync", &data));
+        let msg = compression.apply(&evt, json!({"room": room_id, "event": "sync", "data": data}));
+        assert_eq!(msg["data"]["compressed"], "deflate");
+
+        compression.apply(&evt, json!({"room": room_id, "event": "sync", "data": data}));
+        assert_eq!(arena.metrics().compression()["game"].messages, 2);
+
+        let small = ClientEvents::Msg(room_id.clone(), Message::new("sync", &json!({"a": 1})));
+        let msg = compression.apply(&small, json!({"data": {"a": 1}}));
+        assert_eq!(msg["data"], json!({"a": 1}));
+
+        assert_eq!(compression.kinds.borrow().len(), 1);
+        compression.apply(&ClientEvents::CloseRoom(room_id.clone(), "destroyed".to_string()), json!({}));
+        assert!(compression.kinds.borrow().is_empty());
+    }
+}
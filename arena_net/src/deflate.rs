@@ -0,0 +1,319 @@
+use ws_rs;
+use ws_rs::{Frame, Handler, Handshake, Message, OpCode, Request, Response, CloseCode};
+use ws_rs::util::{Token, Timeout};
+use flate2::{Compress, Decompress, Compression, FlushCompress, FlushDecompress};
+
+/// Tail of a deflate block flushed with sync, removed from the sent messages as the extension requires
+const SYNC_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Wrap a websocket handler to accept the permessage-deflate extension (RFC 7692).
+/// The offers asking for a server window smaller than the default are declined.
+///
+/// The extension of ws 0.7 zero-initializes the zlib stream, which panics with the current compilers.
+/// The messages received that inflate to more than the max size close the connection.
+pub struct DeflateHandler<H: Handler> {
+    inner: H,
+    max_message_size: usize,
+    active: bool,
+    compress: Compress,
+    decompress: Decompress,
+    reset_compress: bool,
+    reset_decompress: bool,
+    /// Opcode and payload of a compressed message received in fragments
+    fragments: Option<(OpCode, Vec<u8>)>,
+}
+
+impl<H: Handler> DeflateHandler<H> {
+    pub fn new(inner: H, max_message_size: usize) -> DeflateHandler<H> {
+        DeflateHandler {
+            inner: inner,
+            max_message_size: max_message_size,
+            active: false,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            reset_compress: false,
+            reset_decompress: false,
+            fragments: None,
+        }
+    }
+
+    /// Response to an offer of the client, None if it can't be accepted
+    fn accept_offer(&mut self, offer: &str) -> Option<String> {
+        let mut ext = String::from("permessage-deflate");
+        let mut reset_compress = false;
+        let mut reset_decompress = false;
+
+        for param in offer.split(';').skip(1).map(|p| p.trim()) {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = kv.next().map(|v| v.trim_matches('"'));
+
+            match key {
+                "server_no_context_takeover" => {
+                    reset_compress = true;
+                    ext.push_str("; server_no_context_takeover");
+                },
+                "client_no_context_takeover" => {
+                    reset_decompress = true;
+                    ext.push_str("; client_no_context_takeover");
+                },
+                //any window used by the client can be decompressed
+                "client_max_window_bits" => {},
+                "server_max_window_bits" if value == Some("15") => {},
+                _ => return None
+            }
+        }
+
+        self.reset_compress = reset_compress;
+        self.reset_decompress = reset_decompress;
+        Some(ext)
+    }
+
+    fn deflate(&mut self, input: &[u8]) -> ws_rs::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            if out.len() == out.capacity() {
+                let more = out.capacity();
+                out.reserve(more);
+            }
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&input[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| ws_rs::Error::new(ws_rs::ErrorKind::Internal, e.to_string()))?;
+
+            //the flush is complete when all the input is consumed and there's space left
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&SYNC_TAIL) {
+            let len = out.len() - SYNC_TAIL.len();
+            out.truncate(len);
+        }
+
+        if self.reset_compress {
+            self.compress.reset();
+        }
+
+        Ok(out)
+    }
+
+    fn inflate(&mut self, mut input: Vec<u8>) -> ws_rs::Result<Vec<u8>> {
+        input.extend_from_slice(&SYNC_TAIL);
+
+        let max = self.max_message_size;
+        let mut out = Vec::with_capacity((input.len() * 2 + 64).min(max + 1));
+        let start = self.decompress.total_in();
+
+        loop {
+            //a small message could inflate without limit
+            if out.len() > max {
+                return Err(too_big(max));
+            }
+
+            if out.len() == out.capacity() {
+                let more = out.capacity().min(max + 1 - out.len());
+                out.reserve_exact(more);
+            }
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let written = out.len();
+            self.decompress.decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| ws_rs::Error::new(ws_rs::ErrorKind::Protocol, e.to_string()))?;
+
+            let now_consumed = (self.decompress.total_in() - start) as usize;
+            if now_consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+
+            if now_consumed == consumed && out.len() == written {
+                return Err(ws_rs::Error::new(ws_rs::ErrorKind::Protocol, "Truncated compressed message."));
+            }
+        }
+
+        if self.reset_decompress {
+            self.decompress.reset(false);
+        }
+
+        Ok(out)
+    }
+}
+
+fn too_big(max: usize) -> ws_rs::Error {
+    ws_rs::Error::new(ws_rs::ErrorKind::Capacity, format!("Message bigger than {} bytes.", max))
+}
+
+impl<H: Handler> Handler for DeflateHandler<H> {
+    fn on_request(&mut self, req: &Request) -> ws_rs::Result<Response> {
+        let mut res = self.inner.on_request(req)?;
+        if res.status() != 101 {
+            return Ok(res);
+        }
+
+        let offers: Vec<String> = req.extensions()?.iter()
+            .filter(|ext| ext.trim().starts_with("permessage-deflate"))
+            .map(|ext| ext.to_string())
+            .collect();
+
+        for offer in offers {
+            if let Some(ext) = self.accept_offer(&offer) {
+                res.add_extension(&ext);
+                self.active = true;
+                break;
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> ws_rs::Result<Option<Frame>> {
+        if !self.active {
+            return self.inner.on_frame(frame);
+        }
+
+        let opcode = frame.opcode();
+        let compressed = match opcode {
+            OpCode::Text | OpCode::Binary => frame.has_rsv1(),
+            OpCode::Continue => self.fragments.is_some(),
+            _ => false
+        };
+
+        if !compressed {
+            return self.inner.on_frame(frame);
+        }
+
+        let is_final = frame.is_final();
+        let (opcode, payload) = match self.fragments.take() {
+            Some((first, mut payload)) => {
+                payload.extend(frame.into_data());
+                (first, payload)
+            },
+            None => (opcode, frame.into_data())
+        };
+
+        if payload.len() > self.max_message_size {
+            return Err(too_big(self.max_message_size));
+        }
+
+        if !is_final {
+            self.fragments = Some((opcode, payload));
+            return Ok(None);
+        }
+
+        let data = self.inflate(payload)?;
+        self.inner.on_frame(Frame::message(data, opcode, true))
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> ws_rs::Result<Option<Frame>> {
+        match self.inner.on_send_frame(frame)? {
+            Some(mut frame) => {
+                let data_frame = match frame.opcode() {
+                    OpCode::Text | OpCode::Binary => true,
+                    _ => false
+                };
+
+                if self.active && data_frame {
+                    let data = self.deflate(frame.payload())?;
+                    *frame.payload_mut() = data;
+                    frame.set_rsv1(true);
+                }
+
+                Ok(Some(frame))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> ws_rs::Result<()> {
+        self.inner.on_open(shake)
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws_rs::Result<()> {
+        self.inner.on_message(msg)
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.inner.on_close(code, reason)
+    }
+
+    fn on_error(&mut self, err: ws_rs::Error) {
+        self.inner.on_error(err)
+    }
+
+    fn on_timeout(&mut self, event: Token) -> ws_rs::Result<()> {
+        self.inner.on_timeout(event)
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> ws_rs::Result<()> {
+        self.inner.on_new_timeout(event, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+    impl Handler for Echo {}
+
+    fn handler(offer: &str, max: usize) -> DeflateHandler<Echo> {
+        let mut handler = DeflateHandler::new(Echo, max);
+        assert!(handler.accept_offer(offer).is_some());
+        handler.active = true;
+        handler
+    }
+
+    #[test]
+    fn inflate_reads_what_deflate_writes() {
+        let mut server = handler("permessage-deflate", 1024);
+        let mut client = handler("permessage-deflate", 1024);
+
+        for text in &["hello", "hello again, with context takeover"] {
+            let compressed = client.deflate(text.as_bytes()).unwrap();
+            assert_eq!(server.inflate(compressed).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn inflate_without_context_takeover() {
+        let offer = "permessage-deflate; client_no_context_takeover; server_no_context_takeover";
+        let mut server = handler(offer, 1024);
+        let mut client = handler(offer, 1024);
+
+        for _ in 0..2 {
+            let compressed = client.deflate(b"same message").unwrap();
+            assert_eq!(server.inflate(compressed).unwrap(), b"same message");
+        }
+    }
+
+    #[test]
+    fn inflate_rejects_messages_bigger_than_the_max() {
+        let mut client = handler("permessage-deflate", 10_000_000);
+        let bomb = client.deflate(&vec![0; 10_000_000]).unwrap();
+        assert!(bomb.len() < 20_000);
+
+        let mut server = handler("permessage-deflate", 1024);
+        match server.inflate(bomb) {
+            Err(e) => match e.kind {
+                ws_rs::ErrorKind::Capacity => {},
+                ref kind => panic!("unexpected error {:?}", kind)
+            },
+            Ok(data) => panic!("inflated {} bytes", data.len())
+        }
+    }
+
+    #[test]
+    fn accept_offer_declines_a_small_server_window() {
+        let mut handler = DeflateHandler::new(Echo, 1024);
+        assert!(handler.accept_offer("permessage-deflate; server_max_window_bits=10").is_none());
+        assert_eq!(handler.accept_offer("permessage-deflate; client_max_window_bits").unwrap(), "permessage-deflate");
+    }
+}
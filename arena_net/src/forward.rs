@@ -0,0 +1,57 @@
+use crossbeam_channel as channel;
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::mpsc;
+use tokio::runtime::TaskExecutor;
+use arena_core::{ClientEvents, Connection, Outbound, deliver_events};
+
+/// Spawn a task that delivers the client events of the connection to its transport when they're dispatched.
+/// The task ends when the connection closes or when its channel is reset by a resume.
+/// While the transport is full the events wait in the queue of the connection, the transport wakes up the task.
+pub fn spawn_forward<O: Outbound + 'static>(executor: &TaskExecutor, conn: &Connection, out: O) {
+    let (notify_send, notify_recv) = mpsc::unbounded();
+
+    //wake up the task once to send the events dispatched before the callback was set
+    if let Err(e) = notify_send.unbounded_send(()) {
+        println!("Error: {}", e);
+    }
+
+    conn.on_dispatch(move || {
+        let _ = notify_send.unbounded_send(());
+    });
+
+    executor.spawn(Forward {
+        notify: notify_recv,
+        events: conn.listen(),
+        conn: conn.clone(),
+        out: out,
+    });
+}
+
+struct Forward<O> {
+    notify: mpsc::UnboundedReceiver<()>,
+    events: channel::Receiver<ClientEvents>,
+    conn: Connection,
+    out: O,
+}
+
+impl<O: Outbound> Future for Forward<O> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        //the notifications only wake up the task, the events are read from the queue
+        loop {
+            match self.notify.poll()? {
+                Async::Ready(Some(())) => {},
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break
+            }
+        }
+
+        if deliver_events(&self.conn, &self.events, &mut self.out) {
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+}
@@ -1,8 +1,77 @@
 extern crate arena_core;
 extern crate serde;
+#[macro_use] extern crate serde_derive;
 #[macro_use] extern crate serde_json;
 extern crate ws as ws_rs;
+extern crate futures;
+extern crate tokio;
+extern crate bytes;
+extern crate parking_lot;
+extern crate crossbeam_channel;
+extern crate rmp_serde;
+extern crate serde_cbor;
+extern crate flate2;
+extern crate base64;
+extern crate url;
 
 mod ws;
+mod tcp;
+mod udp;
+mod protocol;
+mod codec;
+mod compress;
+mod deflate;
+mod forward;
+mod auth;
 
-pub use ws::run;
+use std::thread;
+use arena_core::{Adapter, Arena};
+
+pub use ws::{WsAdapter, Settings};
+pub use tcp::TcpAdapter;
+pub use udp::{UdpAdapter, UdpSettings};
+pub use forward::spawn_forward;
+pub use compress::SyncCompression;
+pub use auth::{Authenticator, Credentials};
+pub use codec::{Codec, JsonCodec, MsgPackCodec, CborCodec, codec_by_name};
+
+/// Serve the arena with a websocket adapter
+pub fn run<F: Fn() -> Arena>(addr: &str, handler: F) {
+    run_with_settings(addr, Settings::default(), handler);
+}
+
+pub fn run_with_settings<F: Fn() -> Arena>(addr: &str, settings: Settings, handler: F) {
+    serve(handler(), vec![Box::new(WsAdapter::with_settings(addr, settings))]);
+}
+
+/// Run the arena and serve it with all the adapters at the same time, it blocks until they stop
+pub fn serve(arena: Arena, adapters: Vec<Box<Adapter>>) {
+    let mut arena_mut = arena.clone();
+    thread::spawn(move || {
+        arena_mut.run();
+    });
+
+    let mut arena_ticks = arena.clone();
+    thread::spawn(move || {
+        arena_ticks.run_ticks();
+    });
+
+    let handles: Vec<_> = adapters.into_iter()
+        .map(|mut adapter| {
+            let arena = arena.clone();
+            thread::spawn(move || {
+                let name = adapter.name();
+                println!("Serving with adapter {}", name);
+                if let Err(e) = adapter.listen(arena) {
+                    println!("Error on adapter {}: {}", name, e);
+                }
+            })
+        })
+        .collect();
+
+    for h in handles {
+        if let Err(e) = h.join() {
+            println!("Error joining adapter thread {:?}", e);
+        }
+    }
+}
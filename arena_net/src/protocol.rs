@@ -0,0 +1,151 @@
+use serde_json;
+use arena_core::{RoomEvents, ClientEvents, Message, JsonValue, ArenaError};
+use codec::Codec;
+
+/// Error code sent when a client message can't be parsed
+pub const INVALID_MESSAGE: &str = "invalid_message";
+
+/// Message sent by the clients, it mirrors the outbound envelope `{room, event, data}`
+#[derive(Debug, Deserialize)]
+pub struct InMessage {
+    #[serde(default)]
+    pub room: String,
+    pub event: String,
+    #[serde(default)]
+    pub data: JsonValue
+}
+
+/// Decode a frame and translate it to the RoomEvent that the arena should process
+pub fn parse_message(conn_id: &str, codec: &Codec, buf: &[u8]) -> Result<RoomEvents, String> {
+    let msg: InMessage = codec.decode(buf)
+        .and_then(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Malformed message: {}", e))?;
+
+    to_room_event(conn_id, msg)
+}
+
+pub fn to_room_event(conn_id: &str, msg: InMessage) -> Result<RoomEvents, String> {
+    let conn_id = conn_id.to_string();
+
+    if msg.event == "close_connection" {
+        return Ok(RoomEvents::CloseConnection(conn_id));
+    }
+
+    if msg.room == "" {
+        return Err(format!("The event '{}' needs a room.", msg.event));
+    }
+
+    let evt = match msg.event.as_ref() {
+        "join_room" => RoomEvents::JoinRoom(msg.room, conn_id, msg.data),
+        //the room of the message is the kind of room to join
+        "join_or_create" => RoomEvents::JoinOrCreate(msg.room, conn_id, msg.data),
+        "spectate" => RoomEvents::Spectate(msg.room, conn_id, msg.data),
+        "close_room" => RoomEvents::CloseRoom(msg.room, conn_id),
+        "resync" => RoomEvents::Resync(msg.room, conn_id),
+        _ => RoomEvents::Msg(msg.room, conn_id, Message::new(&msg.event, &msg.data))
+    };
+
+    Ok(evt)
+}
+
+/// Outbound message using the envelope `{room, event, data}`
+pub fn envelope(room: &str, evt: &str, data: &JsonValue) -> JsonValue {
+    json!({
+        "room": room,
+        "event": evt,
+        "data": data
+    })
+}
+
+/// Serialize an outbound message with the codec of the connection
+pub fn encode_message(codec: &Codec, msg: &JsonValue) -> Option<Vec<u8>> {
+    match codec.encode(msg) {
+        Ok(buf) => Some(buf),
+        Err(e) => {
+            println!("Error encoding message with {}: {}", codec.name(), e);
+            None
+        }
+    }
+}
+
+/// Message for an event of the client, CloseConnection has no message because it closes the transport
+pub fn event_message(evt: &ClientEvents) -> Option<JsonValue> {
+    use arena_core::ClientEvents::*;
+
+    let text = match evt {
+        OpenConnection(id, token) => envelope("", "init", &json!({ "id" : id, "token": token })),
+        Msg(room_id, msg) => envelope(room_id, &msg.event, &msg.data),
+        JoinRoom(room_id, opt_err) => {
+            let data = match opt_err {
+                Some(e) => error_data(e),
+                None => json!({ "error": "" })
+            };
+
+            envelope(room_id, "join_room", &data)
+        },
+        RejectJoinOrCreate(kind, e) => {
+            let mut data = error_data(e);
+            data["kind"] = json!(kind);
+            envelope("", "join_or_create", &data)
+        },
+        CloseRoom(room_id, error_reason) => envelope(room_id, "close_room", &json!({
+            "reason": error_reason
+        })),
+        CloseConnection(_) => return None
+    };
+
+    Some(text)
+}
+
+/// Stable code sent to the clients for each error, the display message could change
+pub fn error_code(err: &ArenaError) -> &'static str {
+    match err {
+        ArenaError::RoomNotFound { .. } => "room_not_found",
+        ArenaError::RoomAlreadyExists { .. } => "room_already_exists",
+        ArenaError::RoomFull { .. } => "room_full",
+        ArenaError::RoomNotIdle { .. } => "room_not_idle",
+        ArenaError::NoMainRoom => "no_main_room",
+        ArenaError::ConnectionRejected { .. } => "connection_rejected",
+        ArenaError::InvalidConnection { .. } => "invalid_connection",
+        ArenaError::InvalidResumeToken => "invalid_resume_token",
+        ArenaError::StateBuilderNotFound { .. } => "state_builder_not_found",
+        ArenaError::InvalidSnapshot { .. } => "invalid_snapshot",
+        ArenaError::MatchRulesNotFound { .. } => "match_rules_not_found",
+        ArenaError::PartyTooLarge { .. } => "party_too_large",
+        ArenaError::InvalidMetadata { .. } => "invalid_metadata",
+        ArenaError::Unauthorized { .. } => "unauthorized",
+        ArenaError::AdapterNotListening { .. } => "adapter_not_listening",
+        ArenaError::AdapterFailed { .. } => "adapter_failed",
+    }
+}
+
+pub fn error_data(err: &ArenaError) -> JsonValue {
+    json!({
+        "code": error_code(err),
+        "error": err.to_string()
+    })
+}
+
+/// Message sent to the client when one of his messages can't be processed
+pub fn error_message(code: &str, reason: &str) -> JsonValue {
+    envelope("", "error", &json!({
+        "code": code,
+        "error": reason
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_failed_join_or_create_is_not_sent_as_a_room() {
+        let err = ArenaError::StateBuilderNotFound { kind: "game".to_string() };
+        let msg = event_message(&ClientEvents::RejectJoinOrCreate("game".to_string(), err)).unwrap();
+
+        assert_eq!(msg["event"], "join_or_create");
+        assert_eq!(msg["room"], "");
+        assert_eq!(msg["data"]["kind"], "game");
+        assert_eq!(msg["data"]["code"], "state_builder_not_found");
+    }
+}
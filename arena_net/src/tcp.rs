@@ -0,0 +1,208 @@
+use std::io;
+use std::sync::Arc;
+use bytes::Bytes;
+use futures::{future, Async, Future, Sink, Stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use tokio::codec::{Framed, LengthDelimitedCodec};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Runtime, TaskExecutor};
+use arena_core::{Adapter, Arena, ArenaError, ClientEvents, Outbound, JsonValue};
+use protocol::{parse_message, encode_message, event_message, envelope, error_message, error_code, INVALID_MESSAGE};
+use codec::{Codec, JsonCodec};
+use compress::{SyncCompression, compress_message};
+use forward::spawn_forward;
+
+/// Frames queued for the socket of a connection, the events wait in its outbound queue while it's full
+const MAX_QUEUED_FRAMES: usize = 64;
+
+/// Serve the arena over raw TCP, every frame is a `{room, event, data}` message prefixed by its length
+/// as a 4 bytes big endian integer. The connections can't be resumed, closing the socket closes them.
+/// There is no handshake to authenticate the clients, their connections are anonymous.
+pub struct TcpAdapter {
+    addr: String,
+    codec: Arc<Codec>,
+    sync_compression: Option<usize>,
+}
+
+impl TcpAdapter {
+    pub fn new(addr: &str) -> TcpAdapter {
+        TcpAdapter {
+            addr: addr.to_string(),
+            codec: Arc::new(JsonCodec),
+            sync_compression: None,
+        }
+    }
+
+    /// Codec used by all the connections of the adapter, json by default
+    pub fn set_codec(&mut self, codec: Arc<Codec>) {
+        self.codec = codec;
+    }
+
+    /// Size in bytes above which the data of the sync messages is compressed, None to disable it
+    pub fn set_sync_compression(&mut self, threshold: Option<usize>) {
+        self.sync_compression = threshold;
+    }
+
+    fn error<E: ToString>(&self, e: E) -> ArenaError {
+        ArenaError::AdapterFailed { name: self.name(), reason: e.to_string() }
+    }
+}
+
+impl Adapter for TcpAdapter {
+    fn name(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    fn listen(&mut self, arena: Arena) -> Result<(), ArenaError> {
+        let addr = self.addr.parse().map_err(|e| self.error(e))?;
+        let listener = TcpListener::bind(&addr).map_err(|e| self.error(e))?;
+        let mut runtime = Runtime::new().map_err(|e| self.error(e))?;
+
+        let executor = runtime.executor();
+        let codec = self.codec.clone();
+        let compression = self.sync_compression.map(|t| SyncCompression::new(t, arena.clone()));
+        let server = listener.incoming().for_each(move |socket| {
+            handle_socket(socket, arena.clone(), codec.clone(), compression.clone(), &executor);
+            Ok(())
+        });
+
+        runtime.block_on(server).map_err(|e| self.error(e))
+    }
+}
+
+enum Frame {
+    Data(Bytes),
+    Close,
+}
+
+struct TcpOutbound {
+    send: mpsc::Sender<Frame>,
+    /// Drops the socket when the close can't be queued behind the frames of a client that doesn't read
+    abort: Option<oneshot::Sender<()>>,
+    codec: Arc<Codec>,
+    compression: Option<SyncCompression>,
+}
+
+impl TcpOutbound {
+    fn send(&mut self, frame: Frame) -> bool {
+        match self.send.try_send(frame) {
+            Ok(_) => true,
+            Err(e) => {
+                if e.is_full() {
+                    println!("Error: tcp outbound queue full");
+                } else {
+                    println!("Error: tcp socket already closed");
+                }
+                false
+            }
+        }
+    }
+
+    fn send_msg(&mut self, msg: &JsonValue) -> bool {
+        match encode_message(&*self.codec, msg) {
+            Some(buf) => self.send(Frame::Data(Bytes::from(buf))),
+            None => true
+        }
+    }
+}
+
+impl Outbound for TcpOutbound {
+    fn deliver(&mut self, evt: &ClientEvents) {
+        if let Some(msg) = event_message(evt) {
+            let msg = compress_message(evt, msg, &self.compression);
+            self.send_msg(&msg);
+        }
+    }
+
+    fn close(&mut self, reason: Option<String>) {
+        //tcp has no close reason, it's sent in a message before closing the socket
+        let queued = self.send_msg(&envelope("", "close_connection", &json!({ "reason": reason })))
+            && self.send(Frame::Close);
+
+        if !queued {
+            if let Some(abort) = self.abort.take() {
+                let _ = abort.send(());
+            }
+        }
+    }
+
+    fn ready(&mut self) -> bool {
+        //a closed socket takes the events to discard them
+        match self.send.poll_ready() {
+            Ok(Async::NotReady) => false,
+            _ => true
+        }
+    }
+}
+
+fn handle_socket(socket: TcpStream, mut arena: Arena, codec: Arc<Codec>, compression: Option<SyncCompression>, executor: &TaskExecutor) {
+    if let Err(e) = socket.set_nodelay(true) {
+        println!("Error: {}", e);
+    }
+
+    let (sink, stream) = Framed::new(socket, LengthDelimitedCodec::new()).split();
+
+    let conn = match arena.accept(None, None) {
+        Ok(conn) => conn,
+        Err(e) => {
+            if let Some(buf) = encode_message(&*codec, &error_message(error_code(&e), &e.to_string())) {
+                executor.spawn(sink.send(Bytes::from(buf)).then(|_| Ok(())));
+            }
+            return;
+        }
+    };
+
+    let (out_send, out_recv) = mpsc::channel(MAX_QUEUED_FRAMES);
+    let (abort_send, abort_recv) = oneshot::channel();
+    let mut errors = TcpOutbound { send: out_send.clone(), abort: None, codec: codec.clone(), compression: None };
+    spawn_forward(executor, &conn, TcpOutbound {
+        send: out_send,
+        abort: Some(abort_send),
+        codec: codec.clone(),
+        compression: compression,
+    });
+
+    //the writer ends after sending the frames queued before the close, or at once when it's aborted
+    let abort = abort_recv.then(|r| match r {
+        Ok(()) => Either::A(future::ok(())),
+        Err(_) => Either::B(future::empty())
+    });
+
+    let writer = out_recv
+        .take_while(|frame| Ok(match frame { Frame::Close => false, _ => true }))
+        .filter_map(|frame| match frame { Frame::Data(bytes) => Some(bytes), Frame::Close => None })
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "outbound channel failed"))
+        .forward(sink)
+        .map(|_| ())
+        .select(abort)
+        .map(|_| ())
+        .map_err(|(e, _)| e);
+
+    let id = conn.id.clone();
+    let reader_arena = arena.clone();
+    let reader = stream.for_each(move |frame| {
+        match parse_message(&id, &*codec, &frame) {
+            Ok(evt) => reader_arena.send(evt),
+            Err(e) => {
+                println!("Invalid message from {}: {}", id, e);
+                errors.send_msg(&error_message(INVALID_MESSAGE, &e));
+            }
+        }
+
+        Ok(())
+    });
+
+    //the socket is dropped when any side ends, unless the arena closed it the connection must be closed
+    let id = conn.id.clone();
+    let task = writer.select2(reader).then(move |r| {
+        match r {
+            Ok(Either::A(_)) => {},
+            _ => arena.disconnect(&id, false)
+        }
+
+        Ok(())
+    });
+
+    executor.spawn(task);
+}
@@ -0,0 +1,671 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use futures::task::{self, Task};
+use tokio::runtime::Runtime;
+use arena_core::{Adapter, Arena, ArenaError, ClientEvents, Outbound};
+use protocol::{parse_message, encode_message, event_message, error_message, error_code, INVALID_MESSAGE};
+use codec::{Codec, JsonCodec};
+use compress::{SyncCompression, compress_message};
+use forward::spawn_forward;
+
+/// First byte of every packet, followed by a 4 bytes big endian sequence number and the payload
+const CONNECT: u8 = 0;
+const RELIABLE: u8 = 1;
+const UNRELIABLE: u8 = 2;
+const ACK: u8 = 3;
+const PING: u8 = 4;
+const DISCONNECT: u8 = 5;
+const CHALLENGE: u8 = 6;
+const RELIABLE_PART: u8 = 7;
+
+const HEADER_LEN: usize = 5;
+/// Max size of a datagram received
+const MAX_PACKET_LEN: usize = 65_507;
+/// Max payload of the packets sent, the bigger messages are split so IP doesn't fragment the datagrams
+const MAX_PAYLOAD_LEN: usize = 1200;
+/// Max size of a message joined from the parts sent by a client
+const MAX_MESSAGE_LEN: usize = 65_536;
+/// Reliable packets received out of order kept waiting for the missing ones
+const MAX_PENDING_PACKETS: usize = 256;
+/// Reliable packets sent and not acked yet, the events wait in the queue of the connection past them
+const MAX_UNACKED_PACKETS: usize = 256;
+
+pub struct UdpSettings {
+    /// Time waited for an ack before sending again a reliable packet
+    pub resend_interval: Duration,
+    /// Reliable packet sent this times without an ack drops the connection
+    pub max_resends: u32,
+    /// Time without receiving packets that drops the connection, the clients send pings to keep it alive
+    pub timeout: Duration,
+}
+
+impl Default for UdpSettings {
+    fn default() -> UdpSettings {
+        UdpSettings {
+            resend_interval: Duration::from_millis(100),
+            max_resends: 50,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Serve the arena over UDP with two channels, the state syncs are sent over the unreliable one
+/// and the rest of the messages over the reliable one.
+///
+/// Every packet is `[kind: u8][seq: u32][payload]`, the payload being a `{room, event, data}` message:
+/// - CONNECT: handshake sent by the client until it receives a packet, the payload is an optional resume token.
+///   The server answers with a CHALLENGE whose seq must be sent back as the seq of the CONNECT, so nothing
+///   else is sent to an address that didn't prove that it receives the packets.
+/// - RELIABLE: delivered in order, the receiver answers with an ACK with the same seq the ones it keeps.
+/// - RELIABLE_PART: part of a reliable message too big for a packet, sent and acked like a RELIABLE one.
+///   The receiver joins the payloads of the parts until the RELIABLE packet with the last part.
+/// - UNRELIABLE: the packets older than the last one received are discarded. The syncs too big for a
+///   packet are sent in parts over the reliable channel.
+///
+/// The seqs wrap around, a seq is newer than another if it's less than half the u32 range ahead of it.
+/// - PING: keeps the connection alive, the server answers with another ping.
+/// - DISCONNECT: closes the connection, the payload is the reason.
+///
+/// A missed sync is detected by the client with the seq of the sync message and recovered with a resync.
+/// The clients are not authenticated, their connections are anonymous.
+pub struct UdpAdapter {
+    addr: String,
+    settings: UdpSettings,
+    codec: Arc<Codec>,
+    sync_compression: Option<usize>,
+}
+
+impl UdpAdapter {
+    pub fn new(addr: &str) -> UdpAdapter {
+        UdpAdapter::with_settings(addr, UdpSettings::default())
+    }
+
+    pub fn with_settings(addr: &str, settings: UdpSettings) -> UdpAdapter {
+        UdpAdapter {
+            addr: addr.to_string(),
+            settings: settings,
+            codec: Arc::new(JsonCodec),
+            sync_compression: None,
+        }
+    }
+
+    /// Codec used by all the connections of the adapter, json by default
+    pub fn set_codec(&mut self, codec: Arc<Codec>) {
+        self.codec = codec;
+    }
+
+    /// Size in bytes above which the data of the sync messages is compressed, None to disable it
+    pub fn set_sync_compression(&mut self, threshold: Option<usize>) {
+        self.sync_compression = threshold;
+    }
+
+    fn error<E: ToString>(&self, e: E) -> ArenaError {
+        ArenaError::AdapterFailed { name: self.name(), reason: e.to_string() }
+    }
+}
+
+impl Adapter for UdpAdapter {
+    fn name(&self) -> String {
+        format!("udp://{}", self.addr)
+    }
+
+    fn listen(&mut self, arena: Arena) -> Result<(), ArenaError> {
+        let socket = UdpSocket::bind(&self.addr[..]).map_err(|e| self.error(e))?;
+        socket.set_read_timeout(Some(self.settings.resend_interval)).map_err(|e| self.error(e))?;
+
+        //the events for the clients are forwarded by tasks, the socket is read by this thread
+        let runtime = Runtime::new().map_err(|e| self.error(e))?;
+
+        let mut server = UdpServer {
+            socket: Arc::new(socket),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            arena: arena.clone(),
+            runtime: runtime,
+            cookies: RandomState::new(),
+            codec: self.codec.clone(),
+            compression: self.sync_compression.map(|t| SyncCompression::new(t, arena.clone())),
+        };
+
+        server.run(&self.settings).map_err(|e| self.error(e))
+    }
+}
+
+fn packet(kind: u8, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&[(seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8]);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Compare two seqs allowing them to wrap around
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+fn parse_packet(buf: &[u8]) -> Option<(u8, u32, &[u8])> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+
+    let seq = (buf[1] as u32) << 24 | (buf[2] as u32) << 16 | (buf[3] as u32) << 8 | buf[4] as u32;
+    Some((buf[0], seq, &buf[HEADER_LEN..]))
+}
+
+struct Session {
+    conn_id: String,
+    last_recv: Instant,
+    reliable_seq: u32,
+    unacked: BTreeMap<u32, Unacked>,
+    /// Forward task waiting for the acks to send more events
+    waiting: Option<Task>,
+    next_reliable: u32,
+    /// Reliable payloads received out of order and if they are the last part of their message
+    pending: BTreeMap<u32, (bool, Vec<u8>)>,
+    /// Parts received of the next reliable message, None while discarding a message too big
+    partial: Option<Vec<u8>>,
+    max_message_len: usize,
+    unreliable_seq: u32,
+    last_unreliable: Option<u32>,
+}
+
+struct Unacked {
+    packet: Vec<u8>,
+    sent: Instant,
+    resends: u32,
+}
+
+impl Session {
+    fn new(conn_id: &str) -> Session {
+        Session {
+            conn_id: conn_id.to_string(),
+            last_recv: Instant::now(),
+            reliable_seq: 0,
+            unacked: BTreeMap::new(),
+            waiting: None,
+            next_reliable: 0,
+            pending: BTreeMap::new(),
+            partial: Some(vec![]),
+            max_message_len: MAX_MESSAGE_LEN,
+            unreliable_seq: 0,
+            last_unreliable: None,
+        }
+    }
+
+    /// Build the packets of a message, the reliable ones are split in parts and kept until they're acked
+    fn next_packets(&mut self, reliable: bool, payload: &[u8]) -> Vec<Vec<u8>> {
+        if !reliable && payload.len() <= MAX_PAYLOAD_LEN {
+            let seq = self.unreliable_seq;
+            self.unreliable_seq = self.unreliable_seq.wrapping_add(1);
+            return vec![packet(UNRELIABLE, seq, payload)];
+        }
+
+        let parts: Vec<&[u8]> = if payload.is_empty() {
+            vec![payload]
+        } else {
+            payload.chunks(MAX_PAYLOAD_LEN).collect()
+        };
+
+        let last = parts.len() - 1;
+        parts.into_iter().enumerate()
+            .map(|(i, part)| {
+                let seq = self.reliable_seq;
+                self.reliable_seq = self.reliable_seq.wrapping_add(1);
+
+                let p = packet(if i == last { RELIABLE } else { RELIABLE_PART }, seq, part);
+                self.unacked.insert(seq, Unacked { packet: p.clone(), sent: Instant::now(), resends: 0 });
+                p
+            })
+            .collect()
+    }
+
+    /// Store a reliable payload and return the messages that can be processed in order.
+    /// None if it was discarded, the packet must not be acked so the client sends it again.
+    fn receive_reliable(&mut self, seq: u32, last: bool, payload: &[u8]) -> Option<Vec<Vec<u8>>> {
+        //already delivered or waiting, the ack was lost
+        if seq_after(self.next_reliable, seq) || self.pending.contains_key(&seq) {
+            return Some(vec![]);
+        }
+
+        if self.pending.len() >= MAX_PENDING_PACKETS && seq != self.next_reliable {
+            return None;
+        }
+
+        self.pending.insert(seq, (last, payload.to_vec()));
+
+        let mut ready = vec![];
+        while let Some((last, p)) = self.pending.remove(&self.next_reliable) {
+            self.next_reliable = self.next_reliable.wrapping_add(1);
+
+            let too_big = self.partial.as_ref().map_or(false, |m| m.len() + p.len() > self.max_message_len);
+            if too_big {
+                println!("Error: udp message of {} bigger than {} bytes discarded", self.conn_id, self.max_message_len);
+                self.partial = None;
+            }
+
+            if let Some(message) = &mut self.partial {
+                message.extend_from_slice(&p);
+            }
+
+            if last {
+                ready.extend(self.partial.take());
+                self.partial = Some(vec![]);
+            }
+        }
+
+        Some(ready)
+    }
+
+    fn ack(&mut self, seq: u32) {
+        self.unacked.remove(&seq);
+        if self.unacked.len() < MAX_UNACKED_PACKETS {
+            if let Some(task) = self.waiting.take() {
+                task.notify();
+            }
+        }
+    }
+
+    /// Only the unreliable payloads newer than the last one are processed
+    fn receive_unreliable(&mut self, seq: u32) -> bool {
+        match self.last_unreliable {
+            Some(last) if !seq_after(seq, last) => false,
+            _ => {
+                self.last_unreliable = Some(seq);
+                true
+            }
+        }
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
+
+struct UdpOutbound {
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    sessions: Sessions,
+    codec: Arc<Codec>,
+    compression: Option<SyncCompression>,
+}
+
+impl Outbound for UdpOutbound {
+    fn deliver(&mut self, evt: &ClientEvents) {
+        let opt_buf = event_message(evt)
+            .map(|msg| compress_message(evt, msg, &self.compression))
+            .and_then(|msg| encode_message(&*self.codec, &msg));
+
+        let buf = match opt_buf {
+            Some(buf) => buf,
+            None => return
+        };
+
+        //the patches can be lost, the client asks for a resync when it misses one
+        let reliable = match evt {
+            ClientEvents::Msg(_, msg) => msg.event != "sync",
+            _ => true
+        };
+
+        let packets = self.sessions.lock()
+            .get_mut(&self.addr)
+            .map(|s| s.next_packets(reliable, &buf))
+            .unwrap_or_default();
+
+        for p in packets {
+            send_packet(&self.socket, &self.addr, &p);
+        }
+    }
+
+    fn close(&mut self, reason: Option<String>) {
+        self.sessions.lock().remove(&self.addr);
+        let reason = reason.unwrap_or("".to_string());
+        send_packet(&self.socket, &self.addr, &packet(DISCONNECT, 0, reason.as_bytes()));
+    }
+
+    fn ready(&mut self) -> bool {
+        //a dropped session takes the events to discard them
+        match self.sessions.lock().get_mut(&self.addr) {
+            Some(s) if s.unacked.len() >= MAX_UNACKED_PACKETS => {
+                s.waiting = Some(task::current());
+                false
+            },
+            _ => true
+        }
+    }
+}
+
+fn send_packet(socket: &UdpSocket, addr: &SocketAddr, packet: &[u8]) {
+    if let Err(e) = socket.send_to(packet, addr) {
+        println!("Error sending udp packet to {}: {}", addr, e);
+    }
+}
+
+struct UdpServer {
+    socket: Arc<UdpSocket>,
+    sessions: Sessions,
+    arena: Arena,
+    runtime: Runtime,
+    /// Randomly keyed hasher of the addresses used for the challenges of the handshake
+    cookies: RandomState,
+    codec: Arc<Codec>,
+    compression: Option<SyncCompression>,
+}
+
+impl UdpServer {
+    fn run(&mut self, settings: &UdpSettings) -> io::Result<()> {
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        let mut last_check = Instant::now();
+
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) => self.on_packet(&buf[..len], addr),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(e)
+            }
+
+            if last_check.elapsed() >= settings.resend_interval {
+                self.check_sessions(settings);
+                last_check = Instant::now();
+            }
+        }
+    }
+
+    fn on_packet(&mut self, buf: &[u8], addr: SocketAddr) {
+        let (kind, seq, payload) = match parse_packet(buf) {
+            Some(p) => p,
+            None => return
+        };
+
+        if kind == CONNECT {
+            self.on_connect(addr, seq, payload);
+            return;
+        }
+
+        let mut sessions = self.sessions.lock();
+        let session = match sessions.get_mut(&addr) {
+            Some(s) => s,
+            None => {
+                send_packet(&self.socket, &addr, &packet(DISCONNECT, 0, b"unknown_session"));
+                return;
+            }
+        };
+
+        session.last_recv = Instant::now();
+        let conn_id = session.conn_id.clone();
+
+        let payloads = match kind {
+            RELIABLE | RELIABLE_PART => match session.receive_reliable(seq, kind == RELIABLE, payload) {
+                Some(ready) => {
+                    send_packet(&self.socket, &addr, &packet(ACK, seq, &[]));
+                    ready
+                },
+                None => vec![]
+            },
+            UNRELIABLE if session.receive_unreliable(seq) => vec![payload.to_vec()],
+            ACK => {
+                session.ack(seq);
+                vec![]
+            },
+            PING => {
+                send_packet(&self.socket, &addr, &packet(PING, seq, &[]));
+                vec![]
+            },
+            DISCONNECT => {
+                sessions.remove(&addr);
+                drop(sessions);
+                self.arena.disconnect(&conn_id, false);
+                return;
+            },
+            _ => vec![]
+        };
+
+        //a reply is needed for the invalid messages, it's queued on the reliable channel
+        let mut replies = vec![];
+        for p in payloads {
+            match parse_message(&conn_id, &*self.codec, &p) {
+                Ok(evt) => self.arena.send(evt),
+                Err(e) => {
+                    println!("Invalid message from {}: {}", conn_id, e);
+                    let opt_buf = encode_message(&*self.codec, &error_message(INVALID_MESSAGE, &e));
+                    if let (Some(s), Some(buf)) = (sessions.get_mut(&addr), opt_buf) {
+                        replies.extend(s.next_packets(true, &buf));
+                    }
+                }
+            }
+        }
+
+        drop(sessions);
+        for p in replies {
+            send_packet(&self.socket, &addr, &p);
+        }
+    }
+
+    /// Cookie of an address sent on the challenge, it's never 0 that is the seq of the first CONNECT
+    fn cookie(&self, addr: &SocketAddr) -> u32 {
+        let mut hasher = self.cookies.build_hasher();
+        addr.hash(&mut hasher);
+        hasher.finish() as u32 | 1
+    }
+
+    fn on_connect(&mut self, addr: SocketAddr, cookie: u32, payload: &[u8]) {
+        //the handshake is repeated until the client receives a packet
+        if self.sessions.lock().contains_key(&addr) {
+            return;
+        }
+
+        //the challenge is as small as the CONNECT so it can't be used to amplify a spoofed request
+        let expected = self.cookie(&addr);
+        if cookie != expected {
+            send_packet(&self.socket, &addr, &packet(CHALLENGE, expected, &[]));
+            return;
+        }
+
+        let token = str::from_utf8(payload).ok().filter(|t| !t.is_empty());
+        match self.arena.accept(token, None) {
+            Ok(conn) => {
+                self.sessions.lock().insert(addr, Session::new(&conn.id));
+                spawn_forward(&self.runtime.executor(), &conn, UdpOutbound {
+                    addr: addr,
+                    socket: self.socket.clone(),
+                    sessions: self.sessions.clone(),
+                    codec: self.codec.clone(),
+                    compression: self.compression.clone(),
+                });
+            },
+            Err(e) => {
+                send_packet(&self.socket, &addr, &packet(DISCONNECT, 0, error_code(&e).as_bytes()));
+            }
+        }
+    }
+
+    /// Send again the reliable packets not acked and drop the sessions that stopped answering
+    fn check_sessions(&mut self, settings: &UdpSettings) {
+        let mut dropped = vec![];
+
+        {
+            let mut sessions = self.sessions.lock();
+            for (addr, session) in sessions.iter_mut() {
+                let lost = session.unacked.values().any(|u| u.resends >= settings.max_resends);
+                if lost || session.last_recv.elapsed() >= settings.timeout {
+                    dropped.push(*addr);
+                    continue;
+                }
+
+                for u in session.unacked.values_mut() {
+                    if u.sent.elapsed() >= settings.resend_interval {
+                        send_packet(&self.socket, addr, &u.packet);
+                        u.sent = Instant::now();
+                        u.resends += 1;
+                    }
+                }
+            }
+        }
+
+        for addr in dropped {
+            let opt_session = self.sessions.lock().remove(&addr);
+            if let Some(session) = opt_session {
+                println!("Udp connection {} from {} dropped", session.conn_id, addr);
+                self.arena.disconnect(&session.conn_id, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arena_core::{JsonValue, Message};
+    use serde_json;
+
+    fn receive(session: &mut Session, seq: u32) -> Option<Vec<u32>> {
+        session.receive_reliable(seq, true, &seq.to_be_bytes())
+            .map(|ready| ready.iter().map(|p| (p[0] as u32) << 24 | (p[1] as u32) << 16 | (p[2] as u32) << 8 | p[3] as u32).collect())
+    }
+
+    #[test]
+    fn reliable_packets_are_delivered_in_order() {
+        let mut session = Session::new("conn");
+        assert_eq!(receive(&mut session, 1), Some(vec![]));
+        assert_eq!(receive(&mut session, 2), Some(vec![]));
+        assert_eq!(receive(&mut session, 0), Some(vec![0, 1, 2]));
+        assert_eq!(receive(&mut session, 3), Some(vec![3]));
+    }
+
+    #[test]
+    fn reliable_packets_received_again_are_acked_but_not_delivered() {
+        let mut session = Session::new("conn");
+        assert_eq!(receive(&mut session, 0), Some(vec![0]));
+        //the ack was lost and the client sent it again
+        assert_eq!(receive(&mut session, 0), Some(vec![]));
+
+        assert_eq!(receive(&mut session, 2), Some(vec![]));
+        assert_eq!(receive(&mut session, 2), Some(vec![]));
+        assert_eq!(receive(&mut session, 1), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn reliable_packets_are_not_acked_with_the_pending_buffer_full() {
+        let mut session = Session::new("conn");
+        for seq in 1..(MAX_PENDING_PACKETS as u32 + 1) {
+            assert_eq!(receive(&mut session, seq), Some(vec![]));
+        }
+
+        //the client must send it again
+        assert_eq!(receive(&mut session, MAX_PENDING_PACKETS as u32 + 1), None);
+
+        //the missing one is always accepted
+        let ready = receive(&mut session, 0).unwrap();
+        assert_eq!(ready.len(), MAX_PENDING_PACKETS + 1);
+        assert_eq!(receive(&mut session, MAX_PENDING_PACKETS as u32 + 1), Some(vec![MAX_PENDING_PACKETS as u32 + 1]));
+    }
+
+    #[test]
+    fn reliable_seq_wraps_around() {
+        let mut session = Session::new("conn");
+        session.next_reliable = ::std::u32::MAX;
+
+        assert_eq!(receive(&mut session, 0), Some(vec![]));
+        assert_eq!(receive(&mut session, ::std::u32::MAX), Some(vec![::std::u32::MAX, 0]));
+        assert_eq!(receive(&mut session, ::std::u32::MAX), Some(vec![]));
+        assert_eq!(receive(&mut session, 1), Some(vec![1]));
+    }
+
+    #[test]
+    fn unreliable_packets_older_than_the_last_are_discarded() {
+        let mut session = Session::new("conn");
+        assert!(session.receive_unreliable(0));
+        assert!(session.receive_unreliable(2));
+        //reordered and lost
+        assert!(!session.receive_unreliable(1));
+        assert!(!session.receive_unreliable(2));
+        assert!(session.receive_unreliable(5));
+    }
+
+    #[test]
+    fn unreliable_seq_wraps_around() {
+        let mut session = Session::new("conn");
+        assert!(session.receive_unreliable(::std::u32::MAX - 1));
+        assert!(session.receive_unreliable(1));
+        assert!(!session.receive_unreliable(::std::u32::MAX));
+        assert!(session.receive_unreliable(2));
+    }
+
+    #[test]
+    fn an_oversized_snapshot_is_split_in_parts_and_joined() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = client.local_addr().unwrap();
+
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        sessions.lock().insert(addr, Session::new("conn"));
+        let mut outbound = UdpOutbound {
+            addr: addr,
+            socket: socket,
+            sessions: sessions.clone(),
+            codec: Arc::new(JsonCodec),
+            compression: None,
+        };
+
+        //bigger than a datagram
+        let state = json!({"board": vec!["cell"; 10_000]});
+        outbound.deliver(&ClientEvents::Msg("room".to_string(), Message::new("snapshot", &state)));
+
+        let mut packets = vec![];
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        loop {
+            let (len, _) = client.recv_from(&mut buf).unwrap();
+            assert!(len <= HEADER_LEN + MAX_PAYLOAD_LEN);
+            packets.push(buf[..len].to_vec());
+            if buf[0] == RELIABLE {
+                break;
+            }
+        }
+
+        assert!(packets.len() > MAX_PACKET_LEN / MAX_PAYLOAD_LEN);
+        assert_eq!(sessions.lock()[&addr].unacked.len(), packets.len());
+
+        //the parts are joined in order whatever the order they arrive
+        let mut receiver = Session::new("client");
+        receiver.max_message_len = ::std::usize::MAX;
+        let mut messages = vec![];
+        for p in packets.iter().rev() {
+            let (kind, seq, payload) = parse_packet(p).unwrap();
+            messages.extend(receiver.receive_reliable(seq, kind == RELIABLE, payload).unwrap());
+        }
+
+        assert_eq!(messages.len(), 1);
+        let msg: JsonValue = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!(msg["event"], "snapshot");
+        assert_eq!(msg["data"], state);
+    }
+
+    #[test]
+    fn syncs_too_big_for_a_packet_are_reliable() {
+        let mut session = Session::new("conn");
+        assert_eq!(session.next_packets(false, &[0; MAX_PAYLOAD_LEN])[0][0], UNRELIABLE);
+
+        let kinds: Vec<u8> = session.next_packets(false, &[0; MAX_PAYLOAD_LEN + 1]).iter().map(|p| p[0]).collect();
+        assert_eq!(kinds, vec![RELIABLE_PART, RELIABLE]);
+        assert_eq!(session.unacked.len(), 2);
+    }
+
+    #[test]
+    fn joined_messages_bigger_than_the_limit_are_discarded() {
+        let mut session = Session::new("conn");
+        let part = vec![0; MAX_PAYLOAD_LEN];
+        let parts = MAX_MESSAGE_LEN / MAX_PAYLOAD_LEN + 1;
+        for seq in 0..parts as u32 {
+            assert_eq!(session.receive_reliable(seq, false, &part), Some(vec![]));
+        }
+
+        assert_eq!(session.receive_reliable(parts as u32, true, &part), Some(vec![]));
+        //the next message is received
+        assert_eq!(session.receive_reliable(parts as u32 + 1, true, b"ok"), Some(vec![b"ok".to_vec()]));
+    }
+}
@@ -1,86 +1,317 @@
 use ws_rs;
-use std::thread;
-use arena_core::{Arena, Connection, ClientEvents, JsonValue};
+use deflate::DeflateHandler;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use url::form_urlencoded;
+use futures::Future;
+use futures::task::{self, Task};
+use tokio::runtime::{Runtime, TaskExecutor};
+use arena_core::{Adapter, Arena, ArenaError, ClientEvents, Identity, Outbound, JsonValue};
+use protocol::{parse_message, encode_message, event_message, error_message, error_code, INVALID_MESSAGE};
+use codec::{Codec, JsonCodec, codec_by_name};
+use compress::{SyncCompression, compress_message};
+use forward::spawn_forward;
+use auth::{Authenticator, Credentials};
+
+pub struct Settings {
+    /// Max number of websockets open at the same time
+    pub max_connections: usize,
+    /// Accept the permessage-deflate extension when the client offers it
+    pub permessage_deflate: bool,
+    /// Max size in bytes of a compressed message once inflated, the bigger ones close the connection
+    pub max_message_size: usize,
+    /// Size in bytes above which the data of the sync messages is compressed, None to disable it
+    pub sync_compression: Option<usize>,
+    /// Bytes sent to a client before it acks them with the pong of a ping, the events wait in the queue
+    /// of the connection past them. The sent messages must be smaller than the max message size.
+    pub max_in_flight: usize,
+    /// Check the credentials of the handshake, None to accept anonymous clients
+    pub authenticator: Option<Arc<Authenticator>>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            max_connections: 10_000,
+            permessage_deflate: false,
+            max_message_size: 1 << 20,
+            sync_compression: None,
+            max_in_flight: 256 << 10,
+            authenticator: None,
+        }
+    }
+}
+
+/// Serve the arena over websockets, the clients can resume a dropped connection with `/?resume=token`.
+/// The codec is chosen with `/?codec=msgpack` or offering its name as subprotocol, json by default.
+/// With an authenticator the handshakes with invalid credentials are rejected with a 401.
+pub struct WsAdapter {
+    addr: String,
+    settings: Settings,
+}
+
+impl WsAdapter {
+    pub fn new(addr: &str) -> WsAdapter {
+        WsAdapter::with_settings(addr, Settings::default())
+    }
+
+    pub fn with_settings(addr: &str, settings: Settings) -> WsAdapter {
+        WsAdapter {
+            addr: addr.to_string(),
+            settings: settings,
+        }
+    }
+
+    fn error(&self, e: ws_rs::Error) -> ArenaError {
+        ArenaError::AdapterFailed { name: self.name(), reason: e.to_string() }
+    }
+}
+
+impl Adapter for WsAdapter {
+    fn name(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    fn listen(&mut self, arena: Arena) -> Result<(), ArenaError> {
+        //the events for the clients are forwarded by tasks instead of a thread per connection
+        let runtime = Runtime::new()
+            .map_err(|e| ArenaError::AdapterFailed { name: self.name(), reason: e.to_string() })?;
+
+        let executor = runtime.executor();
+        let compression = self.settings.sync_compression.map(|t| SyncCompression::new(t, arena.clone()));
+        let authenticator = self.settings.authenticator.clone();
+        let max_in_flight = self.settings.max_in_flight;
+        let new_conn = |out| WsConn {
+            id: None,
+            out: out,
+            in_flight: Arc::new(Mutex::new(InFlight::default())),
+            max_in_flight: max_in_flight,
+            arena: arena.clone(),
+            executor: executor.clone(),
+            codec: Arc::new(JsonCodec),
+            compression: compression.clone(),
+            authenticator: authenticator.clone(),
+            identity: None,
+        };
+
+        let mut builder = ws_rs::Builder::new();
+        //the unsent data of a connection is the bytes in flight and the last message at most
+        builder.with_settings(ws_rs::Settings {
+            max_connections: self.settings.max_connections,
+            out_buffer_capacity: self.settings.max_in_flight + self.settings.max_message_size,
+            out_buffer_grow: false,
+            ..ws_rs::Settings::default()
+        });
+
+        let result = if self.settings.permessage_deflate {
+            let max_message_size = self.settings.max_message_size;
+            builder.build(|out| DeflateHandler::new(new_conn(out), max_message_size))
+                .and_then(|ws| ws.listen(&self.addr[..]).map(|_| ()))
+        } else {
+            builder.build(new_conn)
+                .and_then(|ws| ws.listen(&self.addr[..]).map(|_| ()))
+        };
+
+        runtime.shutdown_now().wait().ok();
+        result.map_err(|e| self.error(e))
+    }
+}
+
+/// Build the frame of a message, the binary codecs use binary frames
+fn ws_message(codec: &Codec, msg: &JsonValue) -> Option<ws_rs::Message> {
+    let buf = encode_message(codec, msg)?;
+    if codec.is_binary() {
+        Some(ws_rs::Message::Binary(buf))
+    } else {
+        String::from_utf8(buf).ok().map(ws_rs::Message::Text)
+    }
+}
+
+/// Bytes of the messages sent to a client, a ping with the bytes sent before it is acked by its pong
+#[derive(Default)]
+struct InFlight {
+    sent: u64,
+    pinged: u64,
+    acked: u64,
+    /// Forward task waiting for the pongs to send more events
+    waiting: Option<Task>,
+}
+
+impl InFlight {
+    fn ping(&mut self, out: &ws_rs::Sender) {
+        let sent = self.sent;
+        let payload = (0..8).rev().map(|i| (sent >> (i * 8)) as u8).collect();
+        match out.ping(payload) {
+            Ok(_) => self.pinged = sent,
+            Err(e) => println!("Error: {}", e)
+        }
+    }
+
+    fn on_pong(&mut self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+
+        //a client can't ack what wasn't pinged
+        let acked = payload.iter().fold(0, |n, b| n << 8 | *b as u64).min(self.pinged);
+        if acked > self.acked {
+            self.acked = acked;
+            if let Some(task) = self.waiting.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+struct WsOutbound {
+    out: ws_rs::Sender,
+    codec: Arc<Codec>,
+    compression: Option<SyncCompression>,
+    in_flight: Arc<Mutex<InFlight>>,
+    max_in_flight: usize,
+}
+
+impl Outbound for WsOutbound {
+    fn deliver(&mut self, evt: &ClientEvents) {
+        let opt_msg = event_message(evt)
+            .map(|m| compress_message(evt, m, &self.compression))
+            .and_then(|m| ws_message(&*self.codec, &m));
+
+        if let Some(msg) = opt_msg {
+            let len = msg.len() as u64;
+            if let Err(e) = self.out.send(msg) {
+                println!("Error: {}",e);
+                return;
+            }
+
+            //the acks arrive while sending, not only once the client is full
+            let mut in_flight = self.in_flight.lock();
+            in_flight.sent += len;
+            if in_flight.sent - in_flight.pinged >= self.max_in_flight as u64 / 4 {
+                in_flight.ping(&self.out);
+            }
+        }
+    }
+
+    fn close(&mut self, reason: Option<String>) {
+        if let Err(e) = self.out.close_with_reason(ws_rs::CloseCode::Normal, reason.unwrap_or("".to_string())) {
+            println!("Error: {}", e); //todo improve how the errors are managed
+        }
+    }
+
+    fn ready(&mut self) -> bool {
+        let mut in_flight = self.in_flight.lock();
+        if in_flight.sent - in_flight.acked < self.max_in_flight as u64 {
+            return true;
+        }
+
+        if in_flight.pinged < in_flight.sent {
+            in_flight.ping(&self.out);
+        }
+
+        in_flight.waiting = Some(task::current());
+        false
+    }
+}
 
 struct WsConn {
     id: Option<String>,
     out: ws_rs::Sender,
+    in_flight: Arc<Mutex<InFlight>>,
+    max_in_flight: usize,
     arena: Arena,
+    executor: TaskExecutor,
+    codec: Arc<Codec>,
+    compression: Option<SyncCompression>,
+    authenticator: Option<Arc<Authenticator>>,
+    /// User authenticated on the handshake
+    identity: Option<Identity>,
 }
 
 impl WsConn {
-    pub fn new(out: ws_rs::Sender, arena: Arena) -> WsConn {
-        WsConn {
-            id: None,
-            out: out,
-            arena: arena
+
+    fn send_error(&self, code: &str, reason: &str) -> ws_rs::Result<()> {
+        match ws_message(&*self.codec, &error_message(code, reason)) {
+            Some(msg) => self.out.send(msg),
+            None => Ok(())
+        }
+    }
+
+    fn on_data(&mut self, codec: &Codec, buf: &[u8]) -> ws_rs::Result<()> {
+        if let Some(id) = &self.id {
+            match parse_message(id, codec, buf) {
+                Ok(evt) => self.arena.send(evt),
+                Err(e) => {
+                    println!("Invalid message from {}: {}", id, e);
+                    self.send_error(INVALID_MESSAGE, &e)?;
+                }
+            }
         }
+
+        Ok(())
     }
 }
 
+/// Get the decoded value of a query param from the request path (`/?resume=token`)
+fn query_param(resource: &str, key: &str) -> Option<String> {
+    let query = resource.splitn(2, '?').nth(1)?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
 impl ws_rs::Handler for WsConn {
-    fn on_open(&mut self, _: ws_rs::Handshake) -> ws_rs::Result<()> {
-        let r_conn = self.arena.new_conn();
-        match r_conn {
+    fn on_request(&mut self, req: &ws_rs::Request) -> ws_rs::Result<ws_rs::Response> {
+        let mut res = ws_rs::Response::from_request(req)?;
+
+        if let Some(authenticator) = &self.authenticator {
+            match authenticator.authenticate(&Credentials::from_request(req)) {
+                Ok(identity) => self.identity = identity,
+                Err(reason) => {
+                    println!("Handshake rejected: {}", reason);
+                    return Ok(ws_rs::Response::new(401, "Unauthorized", reason.into_bytes()));
+                }
+            }
+        }
+
+        //the query param has priority over the subprotocols offered by the client
+        if let Some(name) = query_param(req.resource(), "codec") {
+            match codec_by_name(&name) {
+                Some(codec) => self.codec = codec,
+                None => {
+                    let body = format!("Unknown codec {}", name).into_bytes();
+                    return Ok(ws_rs::Response::new(400, "Bad Request", body));
+                }
+            }
+        } else {
+            let opt_codec = req.protocols()?.into_iter()
+                .filter_map(|p| codec_by_name(p).map(|c| (p.to_string(), c)))
+                .next();
+
+            if let Some((protocol, codec)) = opt_codec {
+                res.set_protocol(&protocol);
+                self.codec = codec;
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn on_open(&mut self, handshake: ws_rs::Handshake) -> ws_rs::Result<()> {
+        let token = query_param(handshake.request.resource(), "resume");
+        match self.arena.accept(token.as_ref().map(|t| t.as_str()), self.identity.clone()) {
             Ok(conn) => {
                 self.id = Some(conn.id.clone());
-
-                let out = self.out.clone();
-                let send_msg = move |room, evt, data| {
-                    if let Err(e) = out.send(format!("{}", json!({
-                        "room": room,
-                        "event": evt,
-                        "data": data
-                    }))) {
-                        println!("Error: {}",e);
-                    }
-                };
-
-                let out = self.out.clone();
-                thread::spawn(move || {
-                    for evt in conn.listen() {
-                        use arena_core::ClientEvents::*;
-                        
-                        match evt {
-                            OpenConnection(id) => {
-                                send_msg("".to_string(), "init".to_string(), json!({ "id" : id }));
-                            },
-                            Msg(room_id, msg) => {
-                                send_msg(room_id, msg.event, msg.data);
-                            },
-                            JoinRoom(room_id, error_reason) => {
-                                send_msg(
-                                    room_id, 
-                                    "join_room".to_string(),
-                                    json!({
-                                        "error": error_reason.unwrap_or("".to_string())
-                                    })
-                                );
-                            },
-                            CloseRoom(room_id, error_reason) => {
-                                send_msg(
-                                    room_id, 
-                                    "close_room".to_string(),
-                                    json!({
-                                        "reason": error_reason
-                                    })
-                                );
-                            },
-                            CloseConnection(reason) => {
-                                if let Err(e) = out.close_with_reason(
-                                    ws_rs::CloseCode::Normal,
-                                    reason.unwrap_or("".to_string())
-                                ) {
-                                    println!("Error: {}", e); //todo improve how the errors are managed
-                                }
-                            },
-                            _ => {}
-                        }
-                    }
+                spawn_forward(&self.executor, &conn, WsOutbound {
+                    out: self.out.clone(),
+                    codec: self.codec.clone(),
+                    compression: self.compression.clone(),
+                    in_flight: self.in_flight.clone(),
+                    max_in_flight: self.max_in_flight,
                 });
             },
             Err(e) => {
-                if let Err(e) = self.out.close_with_reason(ws_rs::CloseCode::Error, e.clone()) {
+                if let Err(e) = self.out.close_with_reason(ws_rs::CloseCode::Error, error_code(&e)) {
                     println!("Error: {}", e);
                 }
                 //return Err(ws_rs::Error::new(ws_rs::ErrorKind::Internal, e));
@@ -90,36 +321,69 @@ impl ws_rs::Handler for WsConn {
         Ok(())
     }
 
-    fn on_close(&mut self, _code: ws_rs::CloseCode, _reason: &str) {
+    fn on_frame(&mut self, frame: ws_rs::Frame) -> ws_rs::Result<Option<ws_rs::Frame>> {
+        if frame.opcode() == ws_rs::OpCode::Pong {
+            self.in_flight.lock().on_pong(frame.payload());
+        }
+
+        //the check of the default handler
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(ws_rs::Error::new(ws_rs::ErrorKind::Protocol, "Encountered frame with reserved bits set."));
+        }
+
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: ws_rs::CloseCode, _reason: &str) {
         if let Some(id) = &self.id {
-            self.arena.remove_connection(id);
+            //a normal close is requested by the client, any other reason could be a network issue
+            self.arena.disconnect(id, code != ws_rs::CloseCode::Normal);
         }
     }
 
     fn on_message(&mut self, message: ws_rs::Message) -> ws_rs::Result<()> { 
+        //the text frames are always json, the binary ones use the codec of the connection
         match message {
             ws_rs::Message::Text(msg) => {
                 println!("msg received {}",msg);
+                self.on_data(&JsonCodec, msg.as_bytes())
             },
-            _ => {}
+            ws_rs::Message::Binary(buf) => {
+                if self.codec.is_binary() {
+                    let codec = self.codec.clone();
+                    self.on_data(&*codec, &buf)
+                } else {
+                    self.send_error(INVALID_MESSAGE, "Binary messages are not supported.")
+                }
+            }
         }
-        Ok(())
     }
 }
 
-pub fn run<F: Fn() -> Arena>(addr: &str, handler: F) {
-    let arena = handler();
-    let mut arena_mut = arena.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    thread::spawn(move || {
-        arena_mut.run();    
-    });
+    #[test]
+    fn query_param_decodes_the_values() {
+        let resource = "/?codec=msg%70ack&resume=a%2Bb%3D&user=Anna+Lee";
+        assert_eq!(query_param(resource, "codec"), Some("msgpack".to_string()));
+        assert_eq!(query_param(resource, "resume"), Some("a+b=".to_string()));
+        assert_eq!(query_param(resource, "user"), Some("Anna Lee".to_string()));
+        assert_eq!(query_param(resource, "token"), None);
+    }
+
+    #[test]
+    fn pongs_ack_only_the_bytes_pinged() {
+        let mut in_flight = InFlight { sent: 1000, pinged: 600, ..InFlight::default() };
+        in_flight.on_pong(&[0, 0, 0, 0, 0, 0, 0x01, 0xf4]);
+        assert_eq!(in_flight.acked, 500);
 
-    let err = ws_rs::listen(addr, |out| {
-        WsConn::new(out, arena.clone())
-    });
+        in_flight.on_pong(&[0, 0, 0, 0, 0, 0, 0x03, 0xe8]);
+        assert_eq!(in_flight.acked, 600);
 
-    if let Err(e) = err {
-        println!("Error intiating ws-rs {}", e);
+        //the pongs of the client's own pings are ignored
+        in_flight.on_pong(b"ping");
+        assert_eq!(in_flight.acked, 600);
     }
-}
\ No newline at end of file
+}
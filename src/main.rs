@@ -7,8 +7,11 @@ extern crate serde;
 #[macro_use] extern crate log;
 extern crate env_logger;
 
-use arena_core::{ClientHandler, LocalClient, Arena, State, Room, JsonValue, RoomEvents, Connection, Message, EmptyState};
+use arena_core::{ClientHandler, LocalClient, Arena, State, Room, JsonValue, RoomEvents, Connection, Message, EmptyState, Identity, Role, OutboundLimit, OutboundPolicy};
+use arena_net::{WsAdapter, TcpAdapter, UdpAdapter, Settings, Credentials};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 #[derive(Debug, Serialize)]
 struct MainRoom;
@@ -32,37 +35,12 @@ impl State for MainRoom {
         Err("Pipi".to_string())
     }*/
 
-    fn on_connect(&mut self, conn: &str, room: &mut Room, server: &mut Arena) {
-        println!("on open connection [{}] {}:{}", conn, room.kind(), room.id());
-        //find a waiting room or create one
-        let room_id = {
-            let mut id = String::from("");
-
-            for r in server.get_rooms_by_kind("game_room") {
-                let room = r.lock();
-                if !room.is_full() {
-                    id = room.id();
-                    break;
-                }
-            }
-
-            if id != "" {
-                id
-            } else {
-                println!("All game rooms are full, creating a new one...");
-                server.add("game_room", Box::new(GameRoom::new())).unwrap()
-            }
-        };
-
-        //tic tac toe, create a new room for every two players
-        let conn = room.get_conn(&conn);
-        match conn {
-            Some(c) => {
-                if let Err(e) = server.add_connection_to(&room_id, c.clone()) {
-                    println!("ERROR adding connection {}", e);
-                }
-            },
-            _ => ()
+    fn on_connect(&mut self, conn: &str, _options: &JsonValue, room: &mut Room, server: &mut Arena) {
+        let user = room.get_conn(conn).and_then(|c| c.user_id().map(|u| u.to_string()));
+        println!("on open connection [{}] user: {:?} {}:{}", conn, user, room.kind(), room.id());
+        //tic tac toe, a waiting room is joined or a new one is created for every two players
+        if let Err(e) = server.join_or_create(conn, "game_room", &JsonValue::Null) {
+            println!("ERROR adding connection {}", e);
         }
     }
 
@@ -125,9 +103,18 @@ impl State for GameRoom {
         room.set_max_connections(2);
     }
 
-    fn on_connect(&mut self, connection_id: &str, room: &mut Room, _server: &mut Arena) {
+    fn on_connect(&mut self, connection_id: &str, _options: &JsonValue, room: &mut Room, _server: &mut Arena) {
+        if room.role(connection_id) == Some(Role::Spectator) {
+            return;
+        }
+
         self.players.push(connection_id.to_string());
 
+        //remember the number of the player, it decides the token placed on the board
+        if let Some(metadata) = room.metadata(connection_id) {
+            metadata.set("player", self.players.len()).ok();
+        }
+
         if self.players.len() == 2 {
             println!("Starting game {} with players [{} vs {}]", room.id(), self.players.get(0).unwrap(), self.players.get(1).unwrap());
             self.state = GameState::PlayingPlayer1;
@@ -136,7 +123,13 @@ impl State for GameRoom {
 
     fn on_disconnect(&mut self, conn_id: &str, room: &mut Room, server: &mut Arena) {
         println!("on disconnect {}:{}", room.id(), conn_id);
-        //server.remove(&room.id());
+        if !self.players.iter().any(|p| p == conn_id) {
+            return;
+        }
+
+        if let Err(e) = server.remove(&room.id()) {
+            println!("Error removing room {}: {}", room.id(), e);
+        }
     }
 }
 
@@ -144,10 +137,41 @@ struct ConnHandler;
 impl ClientHandler for ConnHandler {}
 
 
+/// Demo authenticator, the user is taken from `/?user=name` or the clients join as anonymous guests.
+/// The guests resume their connections with the token alone.
+fn authenticate(credentials: &Credentials) -> Result<Option<Identity>, String> {
+    match credentials.query("user") {
+        Some("") => Err("Empty user name.".to_string()),
+        Some(user) => Ok(Some(Identity::new(user))),
+        None => Ok(None)
+    }
+}
+
 pub fn main() {
     env_logger::init();
 
-    arena_net::run("127.0.0.1:8088", || {
-        Arena::with_main_room("main_room", Box::new(MainRoom::new()))
+    let mut arena = Arena::with_main_room("main_room", Box::new(MainRoom::new()));
+    arena.set_reconnect_timeout(Some(Duration::from_secs(10)));
+    //the syncs of the slow clients are merged instead of queueing them without limit
+    arena.set_outbound_limit(Some(OutboundLimit::new(256, OutboundPolicy::Coalesce)));
+    arena.register_state("game_room", |_| Ok(Box::new(GameRoom::new())));
+
+    let monitor_arena = arena.clone();
+    thread::spawn(move || {
+        arena_monitor::run_monitor_with_settings("127.0.0.1:8089", monitor_arena, arena_monitor::MonitorSettings {
+            expose_metadata: true,
+        });
     });
+
+    arena_net::serve(arena, vec![
+        Box::new(WsAdapter::with_settings("127.0.0.1:8088", Settings {
+            permessage_deflate: true,
+            sync_compression: Some(1024),
+            authenticator: Some(Arc::new(authenticate)),
+            ..Settings::default()
+        })),
+        //the tcp and udp clients are not authenticated, they connect without an identity
+        Box::new(TcpAdapter::new("127.0.0.1:8087")),
+        Box::new(UdpAdapter::new("127.0.0.1:8086")),
+    ]);
 }
\ No newline at end of file
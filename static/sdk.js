@@ -3,11 +3,73 @@ var ClientStatus;
     ClientStatus[ClientStatus["Connected"] = 0] = "Connected";
     ClientStatus[ClientStatus["Disconected"] = 1] = "Disconected";
 })(ClientStatus || (ClientStatus = {}));
+//apply the json patches generated by the server (add, remove and replace)
+function applyPatch(doc, patch) {
+    for (var _i = 0, patch_1 = patch; _i < patch_1.length; _i++) {
+        var op = patch_1[_i];
+        doc = applyOperation(doc, op);
+    }
+    return doc;
+}
+function applyOperation(doc, op) {
+    if (op.path === "") {
+        return op.op === "remove" ? null : op.value;
+    }
+    var keys = op.path.split("/").slice(1).map(function (k) { return k.replace(/~1/g, "/").replace(/~0/g, "~"); });
+    var last = keys.pop();
+    var parent = doc;
+    for (var _i = 0, keys_1 = keys; _i < keys_1.length; _i++) {
+        var k = keys_1[_i];
+        parent = parent[k];
+        if (parent === undefined || parent === null) {
+            throw new Error("Invalid patch path " + op.path);
+        }
+    }
+    if (Array.isArray(parent)) {
+        var index = last === "-" ? parent.length : parseInt(last, 10);
+        switch (op.op) {
+            case "add":
+                parent.splice(index, 0, op.value);
+                break;
+            case "remove":
+                parent.splice(index, 1);
+                break;
+            case "replace":
+                parent[index] = op.value;
+                break;
+            default: throw new Error("Unsupported patch operation " + op.op);
+        }
+    }
+    else {
+        switch (op.op) {
+            case "add":
+            case "replace":
+                parent[last] = op.value;
+                break;
+            case "remove":
+                delete parent[last];
+                break;
+            default: throw new Error("Unsupported patch operation " + op.op);
+        }
+    }
+    return doc;
+}
 var Client = /** @class */ (function () {
     function Client(url) {
         this.status = ClientStatus.Disconected;
         this.rooms = {};
-        this.conn = new WebSocket("ws://" + url);
+        this.versions = {};
+        this.seqs = {};
+        this.resyncing = {};
+        this.reconnectDelay = 1000;
+        //the messages are handled in order even when one of them needs to be decompressed
+        this.queue = Promise.resolve();
+        this.url = url;
+        this._connect();
+    }
+    Client.prototype._connect = function () {
+        var query = this.token ? "?resume=" + this.token : "";
+        this.conn = new WebSocket("ws://" + this.url + "/" + query);
         var me = this;
         this.conn.onopen = function (evt) {
             me.status = ClientStatus.Connected;
@@ -21,37 +83,141 @@ var Client = /** @class */ (function () {
                 console.error(e);
                 return;
             }
-            me._handle(data);
+            me.queue = me.queue
+                .then(function () { return me._decompress(data); })
+                .then(function (msg) { return me._handle(msg); })
+                .catch(function (e) { return console.error(e); });
         };
         this.conn.onclose = function (evt) {
             me.status = ClientStatus.Disconected;
             me.onDisconnect();
+            //the server keeps the connection for a while if it's not closed normally
+            if (evt.code !== 1000 && me.token) {
+                setTimeout(function () { return me._connect(); }, me.reconnectDelay);
+            }
         };
-    }
+    };
     Client.prototype.onDisconnect = function () {
         console.log("on disconnect");
     };
+    Client.prototype.send = function (room, event, data) {
+        if (data === void 0) { data = {}; }
+        var msg = {
+            room: room,
+            event: event,
+            data: data
+        };
+        this.conn.send(JSON.stringify(msg));
+    };
+    //the options are checked by the room, like a password or the chosen team
+    Client.prototype.joinRoom = function (room, options) {
+        if (options === void 0) { options = {}; }
+        this.send(room, "join_room", options);
+    };
+    //watch a room, the messages sent to it are not handled as player moves
+    Client.prototype.spectate = function (room, options) {
+        if (options === void 0) { options = {}; }
+        this.send(room, "spectate", options);
+    };
+    //join any room of the kind, the server creates one if all of them are full
+    Client.prototype.joinOrCreate = function (kind, options) {
+        if (options === void 0) { options = {}; }
+        this.send(kind, "join_or_create", options);
+    };
+    Client.prototype.leaveRoom = function (room) {
+        this.send(room, "close_room");
+    };
+    Client.prototype.close = function () {
+        this.send("", "close_connection");
+    };
+    //ask for the whole state of a room, the syncs are ignored until the snapshot arrives
+    Client.prototype.resync = function (room) {
+        if (this.resyncing[room]) {
+            return;
+        }
+        this.resyncing[room] = true;
+        this.send(room, "resync");
+    };
+    Client.prototype.onSync = function (room, state) {
+        console.log(this.id, room, state);
+    };
+    //the server compresses the data of the sync messages above a size
+    Client.prototype._decompress = function (msg) {
+        if (!msg.data || msg.data.compressed !== "deflate") {
+            return Promise.resolve(msg);
+        }
+        var bytes = Uint8Array.from(atob(msg.data.payload), function (c) { return c.charCodeAt(0); });
+        var stream = new Blob([bytes]).stream().pipeThrough(new DecompressionStream("deflate"));
+        return new Response(stream).text().then(function (text) {
+            msg.data = JSON.parse(text);
+            return msg;
+        });
+    };
     Client.prototype._handle = function (msg) {
         switch (msg.event) {
             case "init":
                 this.id = msg.data.id;
+                this.token = msg.data.token;
                 break;
             case "join_room":
                 if (msg.data.error) {
-                    console.error("Error joining room " + msg.room + ": " + msg.data.error);
+                    console.error("Error joining room " + msg.room + ": [" + msg.data.code + "] " + msg.data.error);
                 }
                 else {
                     this.rooms[msg.room] = {};
                 }
                 break;
+            case "join_or_create":
+                console.error("Error joining a room of kind " + msg.data.kind + ": [" + msg.data.code + "] " + msg.data.error);
+                break;
+            case "snapshot":
+                this.rooms[msg.room] = msg.data.state;
+                this.versions[msg.room] = msg.data.version;
+                this.seqs[msg.room] = msg.data.seq;
+                delete this.resyncing[msg.room];
+                this.onSync(msg.room, this.rooms[msg.room]);
+                break;
+            case "sync":
+                this._sync(msg);
+                break;
             case "close_room":
                 delete this.rooms[msg.room];
+                delete this.versions[msg.room];
+                delete this.seqs[msg.room];
+                delete this.resyncing[msg.room];
+                break;
+            case "error":
+                console.error("Error from server: [" + msg.data.code + "] " + msg.data.error);
                 break;
             default:
                 console.log(this.id, msg);
                 break;
         }
     };
+    Client.prototype._sync = function (msg) {
+        if (this.resyncing[msg.room]) {
+            return;
+        }
+        var seq = this.seqs[msg.room];
+        //the syncs merged by the server start at from_seq
+        var first = msg.data.from_seq === undefined ? msg.data.seq : msg.data.from_seq;
+        if (first !== seq + 1 || msg.data.from_version !== this.versions[msg.room]) {
+            console.warn("Missed sync on room " + msg.room + ": expected seq " + (seq + 1) + " got " + msg.data.seq);
+            this.resync(msg.room);
+            return;
+        }
+        try {
+            this.rooms[msg.room] = applyPatch(this.rooms[msg.room], msg.data.patch);
+        }
+        catch (e) {
+            console.error(e);
+            this.resync(msg.room);
+            return;
+        }
+        this.seqs[msg.room] = msg.data.seq;
+        this.versions[msg.room] = msg.data.to_version;
+        this.onSync(msg.room, this.rooms[msg.room]);
+    };
     return Client;
 }());
 (function () {
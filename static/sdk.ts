@@ -9,14 +9,78 @@ interface Message {
     data: any
 }
 
+interface PatchOperation {
+    op: string,
+    path: string,
+    value?: any
+}
+
+//apply the json patches generated by the server (add, remove and replace)
+function applyPatch(doc: any, patch: PatchOperation[]): any {
+    for (let op of patch) {
+        doc = applyOperation(doc, op);
+    }
+
+    return doc;
+}
+
+function applyOperation(doc: any, op: PatchOperation): any {
+    if (op.path === "") {
+        return op.op === "remove" ? null : op.value;
+    }
+
+    let keys = op.path.split("/").slice(1).map(k => k.replace(/~1/g, "/").replace(/~0/g, "~"));
+    let last = keys.pop();
+    let parent = doc;
+    for (let k of keys) {
+        parent = parent[k];
+        if (parent === undefined || parent === null) {
+            throw new Error(`Invalid patch path ${op.path}`);
+        }
+    }
+
+    if (Array.isArray(parent)) {
+        let index = last === "-" ? parent.length : parseInt(last, 10);
+        switch(op.op) {
+            case "add": parent.splice(index, 0, op.value); break;
+            case "remove": parent.splice(index, 1); break;
+            case "replace": parent[index] = op.value; break;
+            default: throw new Error(`Unsupported patch operation ${op.op}`);
+        }
+    } else {
+        switch(op.op) {
+            case "add":
+            case "replace": parent[last] = op.value; break;
+            case "remove": delete parent[last]; break;
+            default: throw new Error(`Unsupported patch operation ${op.op}`);
+        }
+    }
+
+    return doc;
+}
+
 class Client {
     status: ClientStatus = ClientStatus.Disconected;
     id: string;
+    token: string;
+    url: string;
     conn: WebSocket;
     rooms: {[id: string] : any} = {};
+    versions: {[id: string] : number} = {};
+    seqs: {[id: string] : number} = {};
+    resyncing: {[id: string] : boolean} = {};
+    reconnectDelay: number = 1000;
+    //the messages are handled in order even when one of them needs to be decompressed
+    queue: Promise<void> = Promise.resolve();
 
     constructor(url: string) {
-        this.conn = new WebSocket(`ws://${url}`);
+        this.url = url;
+        this._connect();
+    }
+
+    _connect() {
+        let query = this.token ? `?resume=${this.token}` : "";
+        this.conn = new WebSocket(`ws://${this.url}/${query}`);
         
         let me = this;
         this.conn.onopen = function(evt){
@@ -32,12 +96,20 @@ class Client {
                 return;
             }
 
-            me._handle(data);
+            me.queue = me.queue
+                .then(() => me._decompress(data))
+                .then(msg => me._handle(msg))
+                .catch(e => console.error(e));
         };
 
         this.conn.onclose = function(evt) {
             me.status = ClientStatus.Disconected;
             me.onDisconnect();
+
+            //the server keeps the connection for a while if it's not closed normally
+            if (evt.code !== 1000 && me.token) {
+                setTimeout(() => me._connect(), me.reconnectDelay);
+            }
         };
     }
 
@@ -45,26 +117,134 @@ class Client {
         console.log("on disconnect");
     }
 
+    send(room: string, event: string, data: any = {}) {
+        let msg: Message = {
+            room: room,
+            event: event,
+            data: data
+        };
+
+        this.conn.send(JSON.stringify(msg));
+    }
+
+    //the options are checked by the room, like a password or the chosen team
+    joinRoom(room: string, options: any = {}) {
+        this.send(room, "join_room", options);
+    }
+
+    //watch a room, the messages sent to it are not handled as player moves
+    spectate(room: string, options: any = {}) {
+        this.send(room, "spectate", options);
+    }
+
+    //join any room of the kind, the server creates one if all of them are full
+    joinOrCreate(kind: string, options: any = {}) {
+        this.send(kind, "join_or_create", options);
+    }
+
+    leaveRoom(room: string) {
+        this.send(room, "close_room");
+    }
+
+    close() {
+        this.send("", "close_connection");
+    }
+
+    //ask for the whole state of a room, the syncs are ignored until the snapshot arrives
+    resync(room: string) {
+        if (this.resyncing[room]) {
+            return;
+        }
+
+        this.resyncing[room] = true;
+        this.send(room, "resync");
+    }
+
+    onSync(room: string, state: any) {
+        console.log(this.id, room, state);
+    }
+
+    //the server compresses the data of the sync messages above a size
+    _decompress(msg:Message): Promise<Message> {
+        if (!msg.data || msg.data.compressed !== "deflate") {
+            return Promise.resolve(msg);
+        }
+
+        let bytes = Uint8Array.from(atob(msg.data.payload), c => c.charCodeAt(0));
+        let stream = new Blob([bytes]).stream().pipeThrough(new DecompressionStream("deflate"));
+        return new Response(stream).text().then(text => {
+            msg.data = JSON.parse(text);
+            return msg;
+        });
+    }
+
     _handle(msg:Message) {
         switch(msg.event) {
             case "init": 
                 this.id = msg.data.id;
+                this.token = msg.data.token;
                 break;
             case "join_room":
                 if(msg.data.error) {
-                    console.error(`Error joining room ${msg.room}: ${msg.data.error}`);
+                    console.error(`Error joining room ${msg.room}: [${msg.data.code}] ${msg.data.error}`);
                 } else {
                     this.rooms[msg.room] = {};
                 }
                 break;
+            case "join_or_create":
+                console.error(`Error joining a room of kind ${msg.data.kind}: [${msg.data.code}] ${msg.data.error}`);
+                break;
+            case "snapshot":
+                this.rooms[msg.room] = msg.data.state;
+                this.versions[msg.room] = msg.data.version;
+                this.seqs[msg.room] = msg.data.seq;
+                delete this.resyncing[msg.room];
+                this.onSync(msg.room, this.rooms[msg.room]);
+                break;
+            case "sync":
+                this._sync(msg);
+                break;
             case "close_room":
                 delete this.rooms[msg.room];
+                delete this.versions[msg.room];
+                delete this.seqs[msg.room];
+                delete this.resyncing[msg.room];
+                break;
+            case "error":
+                console.error(`Error from server: [${msg.data.code}] ${msg.data.error}`);
                 break;
             default: 
                 console.log(this.id, msg);
                 break;
         }
     }
+
+    _sync(msg:Message) {
+        if (this.resyncing[msg.room]) {
+            return;
+        }
+
+        let seq = this.seqs[msg.room];
+        //the syncs merged by the server start at from_seq
+        let first = msg.data.from_seq === undefined ? msg.data.seq : msg.data.from_seq;
+        if (first !== seq + 1 || msg.data.from_version !== this.versions[msg.room]) {
+            console.warn(`Missed sync on room ${msg.room}: expected seq ${seq + 1} got ${msg.data.seq}`);
+            this.resync(msg.room);
+            return;
+        }
+
+        try {
+            this.rooms[msg.room] = applyPatch(this.rooms[msg.room], msg.data.patch);
+        } catch(e) {
+            console.error(e);
+            this.resync(msg.room);
+            return;
+        }
+
+        this.seqs[msg.room] = msg.data.seq;
+        this.versions[msg.room] = msg.data.to_version;
+        this.onSync(msg.room, this.rooms[msg.room]);
+    }
 }
 
 